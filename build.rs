@@ -1,20 +1,72 @@
+use std::collections::BTreeSet;
 use std::env;
+use std::fmt::Write;
 use std::path::Path;
 
-use ctl10n;
-
 const LOCALES: &[&str] = &["zh", "en"];
 
+/// Generate the `tr!` macro, with one rule per key so unknown keys fail to compile,
+/// and the strings of every locale to choose from at runtime
 fn main() {
     for locale in LOCALES {
         println!("cargo:rerun-if-changed=locales/{}.toml", locale);
     }
     println!("cargo:rerun-if-env-changed=LOCALE");
-    let locale_file = format!(
-        "locales/{}.toml",
-        &env::var("LOCALE").unwrap_or("zh".to_string())
+    let default_locale = env::var("LOCALE").unwrap_or("zh".to_string());
+    assert!(
+        LOCALES.contains(&&*default_locale),
+        "unknown LOCALE {}",
+        default_locale
     );
-    let out_file = Path::new(&env::var("OUT_DIR").unwrap()).join("ctl10n_macros.rs");
-    let _ignore_error = std::fs::remove_file(&out_file);
-    ctl10n::convert_strings_file(locale_file, out_file).expect("ctl10n failed");
+
+    let mut keys = BTreeSet::new();
+    let mut strings = String::new();
+    writeln!(
+        strings,
+        "pub const DEFAULT_LOCALE: &str = {:?};",
+        default_locale
+    )
+    .unwrap();
+    writeln!(strings, "static STRINGS: &[(&str, &[(&str, &str)])] = &[").unwrap();
+    for locale in LOCALES {
+        let path = format!("locales/{}.toml", locale);
+        let content = std::fs::read_to_string(&path).expect("failed to read locale");
+        let table = match content.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => panic!("{} is not a table", path),
+            Err(e) => panic!("failed to parse {}: {}", path, e),
+        };
+        writeln!(strings, "    ({:?}, &[", locale).unwrap();
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                _ => panic!("{}: {} is not a string", path, key),
+            };
+            writeln!(strings, "        ({:?}, {:?}),", key, value).unwrap();
+            keys.insert(key);
+        }
+        writeln!(strings, "    ]),").unwrap();
+    }
+    writeln!(strings, "];").unwrap();
+
+    let mut macros = String::new();
+    writeln!(macros, "macro_rules! tr {{").unwrap();
+    for key in keys {
+        writeln!(
+            macros,
+            "    ({key:?}) => {{ crate::i18n::translate({key:?}) }};\n    \
+             ({key:?}, $($name:ident = $value:expr),+ $(,)?) => {{\n        \
+             crate::i18n::format(\n            \
+             crate::i18n::translate({key:?}),\n            \
+             &[$((stringify!($name), &$value as &dyn ::std::fmt::Display)),+],\n        \
+             )\n    }};",
+            key = key
+        )
+        .unwrap();
+    }
+    writeln!(macros, "}}").unwrap();
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    std::fs::write(Path::new(&out_dir).join("locales.rs"), strings).unwrap();
+    std::fs::write(Path::new(&out_dir).join("tr_macro.rs"), macros).unwrap();
 }
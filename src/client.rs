@@ -1,17 +1,29 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use once_cell::sync::OnceCell;
 use reqwest::{
     self,
-    header::{HeaderValue, CONTENT_TYPE},
+    header::{
+        HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RETRY_AFTER,
+    },
+    StatusCode, Url,
 };
 use thiserror::Error;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
+};
 
 use crate::feed::Rss;
 
 static RESP_SIZE_LIMIT: OnceCell<u64> = OnceCell::new();
 static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+static HOST_LIMITER: OnceCell<HostLimiter> = OnceCell::new();
 
 #[derive(Error, Debug)]
 pub enum FeedError {
@@ -21,6 +33,18 @@ pub enum FeedError {
     Parsing(#[from] quick_xml::Error),
     #[error("feed is too large")]
     TooLarge(u64),
+    /// A web page instead of a feed, with the feeds it links to
+    #[error("not a feed")]
+    Html(Vec<String>),
+    /// 429 or 503, with the delay from `Retry-After` if present
+    #[error("rate limited")]
+    RateLimited {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// 304 to a request without validators, some servers and caches do this
+    #[error("not modified")]
+    NotModified,
 }
 
 impl FeedError {
@@ -32,18 +56,67 @@ impl FeedError {
                 "rss_size_limit_exceeded",
                 size = format_byte_size((*limit).into())
             ),
+            Self::Html(_) => tr!("not_a_feed").into(),
+            Self::RateLimited { status, .. } => tr!("rate_limited_error", status = status),
+            Self::NotModified => tr!("not_modified_error").into(),
         }
     }
 }
 
+pub struct Pulled {
+    pub feed: Rss,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
 pub async fn pull_feed(url: &str) -> Result<Rss, FeedError> {
-    let mut resp = CLIENT
+    let pulled = pull_feed_if_modified(url, None, None).await?;
+    pulled
+        .map(|pulled| pulled.feed)
+        .ok_or(FeedError::NotModified)
+}
+
+/// Send a conditional request with the given validators,
+/// return `None` if the server says the feed is not modified
+///
+/// Requests to the same host are limited by `HostLimiter`
+pub async fn pull_feed_if_modified(
+    url: &str,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Result<Option<Pulled>, FeedError> {
+    let _permit = HOST_LIMITER
         .get()
-        .expect("CLIENT not initialized")
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?;
+        .expect("HOST_LIMITER not initialized")
+        .acquire(url)
+        .await;
+    let mut req = CLIENT.get().expect("CLIENT not initialized").get(url);
+    if let Some(etag) = etag {
+        req = req.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        req = req.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, SystemTime::now()));
+        return Err(FeedError::RateLimited {
+            status,
+            retry_after,
+        });
+    }
+    let mut resp = resp.error_for_status()?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let etag = header_to_string(&resp, ETAG);
+    let last_modified = header_to_string(&resp, LAST_MODIFIED);
+
     let size_limit = *RESP_SIZE_LIMIT
         .get()
         .expect("RESP_SIZE_LIMIT not initialized");
@@ -54,11 +127,11 @@ pub async fn pull_feed(url: &str) -> Result<Rss, FeedError> {
         }
     }
 
-    let feed = if url.ends_with(".json")
-        || matches!(
-            resp.headers().get(CONTENT_TYPE),
-            Some(v) if content_type_is_json(v)
-        ) {
+    let content_type = resp.headers().get(CONTENT_TYPE);
+    let is_json = matches!(content_type, Some(v) if content_type_is(v, "application/json"));
+    let is_html = matches!(content_type, Some(v) if content_type_is(v, "text/html"));
+    let page_url = resp.url().clone();
+    let feed = if url.ends_with(".json") || is_json {
         resp.json().await?
     } else {
         let mut buf = Vec::new(); // TODO: capacity?
@@ -68,14 +141,27 @@ pub async fn pull_feed(url: &str) -> Result<Rss, FeedError> {
             }
             buf.extend_from_slice(&bytes);
         }
+        if is_html {
+            let html = String::from_utf8_lossy(&buf);
+            let feeds = crate::feed::discover_feeds(&html)
+                .into_iter()
+                .filter_map(|href| page_url.join(&href).ok())
+                .map(String::from)
+                .collect();
+            return Err(FeedError::Html(feeds));
+        }
 
         crate::feed::parse(std::io::Cursor::new(buf))?
     };
 
-    Ok(crate::feed::fix_relative_url(feed, url))
+    Ok(Some(Pulled {
+        feed: crate::feed::fix_relative_url(feed, url),
+        etag,
+        last_modified,
+    }))
 }
 
-pub fn init_client(bot_name: &str, insecue: bool, max_feed_size: u64) {
+pub fn init_client(bot_name: &str, insecue: bool, max_feed_size: u64, host_limiter: HostLimiter) {
     let mut headers = reqwest::header::HeaderMap::new();
     let ua = format!(
         concat!(
@@ -109,17 +195,99 @@ pub fn init_client(bot_name: &str, insecue: bool, max_feed_size: u64) {
     RESP_SIZE_LIMIT
         .set(max_feed_size)
         .expect("RESP_SIZE_LIMIT already initialized");
+    HOST_LIMITER
+        .set(host_limiter)
+        .expect("HOST_LIMITER already initialized");
+}
+
+/// Limits concurrent requests and the spacing between requests to the same host
+#[derive(Debug)]
+pub struct HostLimiter {
+    concurrency: usize,
+    spacing: Duration,
+    hosts: std::sync::Mutex<HashMap<String, HostState>>,
+}
+
+#[derive(Debug)]
+struct HostState {
+    semaphore: Arc<Semaphore>,
+    next_request: Instant,
+}
+
+impl HostState {
+    /// Nobody holds or waits for a permit, and the spacing has passed
+    fn is_idle(&self, now: Instant) -> bool {
+        Arc::strong_count(&self.semaphore) == 1 && self.next_request <= now
+    }
 }
 
-fn content_type_is_json(value: &HeaderValue) -> bool {
+impl HostLimiter {
+    pub fn new(concurrency: usize, spacing: Duration) -> Self {
+        HostLimiter {
+            concurrency,
+            spacing,
+            hosts: Default::default(),
+        }
+    }
+
+    /// Wait for a chance to send a request to the host of `link`,
+    /// the returned permit should be held until the request finished
+    async fn acquire(&self, link: &str) -> Option<OwnedSemaphorePermit> {
+        let host = Url::parse(link).ok()?.host_str()?.to_owned();
+        let semaphore = {
+            let mut hosts = self.hosts.lock().unwrap();
+            if hosts.len() > 1024 {
+                let now = Instant::now();
+                hosts.retain(|_, state| !state.is_idle(now));
+            }
+            hosts
+                .entry(host.clone())
+                .or_insert_with(|| HostState {
+                    semaphore: Arc::new(Semaphore::new(self.concurrency)),
+                    next_request: Instant::now(),
+                })
+                .semaphore
+                .clone()
+        };
+        let permit = semaphore.acquire_owned().await.expect("never closed");
+        let at = {
+            let mut hosts = self.hosts.lock().unwrap();
+            // Hosts with a waiting request aren't idle
+            let state = hosts.get_mut(&host).expect("not removed while in use");
+            let at = cmp::max(state.next_request, Instant::now());
+            state.next_request = at + self.spacing;
+            at
+        };
+        time::sleep_until(at).await;
+        Some(permit)
+    }
+}
+
+fn header_to_string(resp: &reqwest::Response, name: HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// `Retry-After` is either a delay in seconds or an HTTP-date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        SystemTime::from(date)
+            .duration_since(now)
+            .unwrap_or_default(),
+    )
+}
+
+fn content_type_is(value: &HeaderValue, mime: &str) -> bool {
     value
         .to_str()
-        .map(|value| {
-            value
-                .split(';')
-                .map(|v| v.trim())
-                .any(|v| v == "application/json")
-        })
+        .map(|value| value.split(';').map(|v| v.trim()).any(|v| v == mime))
         .unwrap_or(false)
 }
 
@@ -172,4 +340,35 @@ mod test {
 
         assert_eq!(format_byte_size(1024 + 10), "1kiB");
     }
+
+    #[tokio::test]
+    async fn host_limiter_removes_idle_hosts() {
+        let limiter = HostLimiter::new(1, Duration::ZERO);
+        let _permit = limiter.acquire("http://busy.com/feed.xml").await;
+        for i in 0..1100 {
+            let link = format!("http://{}.example.com/feed.xml", i);
+            limiter.acquire(&link).await;
+        }
+        let hosts = limiter.hosts.lock().unwrap();
+        assert!(hosts.len() <= 1025);
+        assert!(hosts.contains_key("busy.com"));
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1445412480);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
 }
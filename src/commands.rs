@@ -1,14 +1,34 @@
 use std::sync::Arc;
 
-use tbot::{contexts::Command, types::parameters, Bot};
+use tbot::{
+    contexts::Command,
+    types::{chat::Chat, keyboard::inline::Keyboard, message, parameters},
+    Bot,
+};
 use tokio::sync::Mutex;
 
 use crate::data::Database;
+use crate::i18n::{self, Lang};
+use crate::sender::wait_for_slot;
 
+mod digest;
+mod display;
 mod export;
+mod filter;
+mod import;
+mod lang;
+mod manage;
+mod media;
+mod pause;
+mod preview;
+mod quiet;
+mod resume;
 mod rss;
+mod settings;
 mod start;
+mod status;
 mod sub;
+mod template;
 mod unsub;
 
 macro_rules! add_handlers {
@@ -20,11 +40,16 @@ macro_rules! add_handlers {
                 let env = env.clone();
                 let opt = opt.clone();
                 async move {
-                    if check_command(&opt, &cmd).await {
-                        if let Err(e) = self::$cmd::$cmd(env, cmd).await {
-                            crate::print_error(e);
+                    let lang = command_lang(&env, &cmd).await;
+                    i18n::scope(lang, async move {
+                        if check_command(&opt, &cmd).await {
+                            remember_lang(&env, cmd.chat.id.0, lang).await;
+                            if let Err(e) = self::$cmd::$cmd(env, cmd).await {
+                                crate::print_error(e);
+                            }
                         }
-                    }
+                    })
+                    .await
                 }
             };
             $event_loop.command(stringify!($cmd), h);
@@ -37,35 +62,82 @@ pub fn register_commands(
     opt: Arc<crate::Opt>,
     db: Arc<Mutex<Database>>,
 ) {
-    add_handlers!(event_loop, opt, db, [start, rss, sub, unsub, export]);
+    add_handlers!(
+        event_loop,
+        opt,
+        db,
+        [
+            start, rss, sub, unsub, pause, resume, export, import, filter, media, preview, display,
+            template, digest, quiet, lang, settings, status
+        ]
+    );
+    import::register_caption(event_loop, opt.clone(), db.clone());
+    manage::register_callback(event_loop, opt, db);
 }
 
-pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
-    use tbot::types::chat::Kind::*;
-    let reply_target = &mut MsgTarget::new(cmd.chat.id, cmd.message_id);
+/// The language chosen with /lang, or else the Telegram language of the user
+async fn command_lang(db: &Mutex<Database>, cmd: &Command) -> Lang {
+    sender_lang(db, cmd.chat.id, cmd.from.as_ref()).await
+}
+
+async fn sender_lang(
+    db: &Mutex<Database>,
+    chat_id: tbot::types::chat::Id,
+    from: Option<&message::From>,
+) -> Lang {
+    let language_code = match from {
+        Some(message::From::User(user)) => user.language_code.as_deref(),
+        _ => None,
+    };
+    db.lock().await.chat(chat_id.0).lang_or(language_code)
+}
+
+/// Remember the language of the first user, so updates are sent in it too
+async fn remember_lang(db: &Mutex<Database>, chat_id: i64, lang: Lang) {
+    let mut db = db.lock().await;
+    let mut chat = db.chat(chat_id);
+    if chat.language.is_none() && lang != i18n::default_lang() {
+        chat.language = Some(lang.code().to_string());
+        db.set_chat(chat_id, chat);
+    }
+}
 
+pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
     // Private mode
-    if !opt.admin.is_empty() && !is_from_bot_admin(&cmd, &opt.admin) {
+    if !opt.admin.is_empty() && !is_from_bot_admin(cmd.from.as_ref(), &opt.admin) {
         eprintln!(
             "Unauthenticated request from user/channel: {:?}, command: {}, args: {}",
             cmd.from, cmd.command, cmd.text.value
         );
         return false;
     }
+    check_chat(opt, &cmd.bot, &cmd.chat, cmd.from.as_ref(), cmd.message_id).await
+}
 
-    match cmd.chat.kind {
+/// Commands aren't accepted in channels, and only from admins in restricted mode
+async fn check_chat(
+    opt: &crate::Opt,
+    bot: &Bot,
+    chat: &Chat,
+    from: Option<&message::From>,
+    message_id: message::Id,
+) -> bool {
+    use tbot::types::chat::Kind::*;
+    let reply_target = &mut MsgTarget::new(chat.id, message_id);
+
+    match chat.kind {
         Channel { .. } => {
             let msg = tr!("commands_in_private_channel");
             let _ignore_result =
-                update_response(&cmd.bot, reply_target, parameters::Text::with_plain(msg)).await;
+                update_response(bot, reply_target, parameters::Text::with_plain(msg)).await;
             return false;
         }
         // Restrict mode: bot commands are only accessible to admins.
         Group { .. } | Supergroup { .. } if opt.restricted => {
-            let user_is_admin = is_from_chat_admin(&cmd).await;
+            let user_is_admin = is_from_chat_admin(bot, chat.id, from).await;
             if !user_is_admin {
                 let _ignore_result = update_response(
-                    &cmd.bot,
+                    bot,
                     reply_target,
                     parameters::Text::with_plain(tr!("group_admin_only_command")),
                 )
@@ -79,9 +151,9 @@ pub async fn check_command(opt: &crate::Opt, cmd: &Command) -> bool {
     true
 }
 
-fn is_from_bot_admin(cmd: &Command, admins: &[i64]) -> bool {
+fn is_from_bot_admin(from: Option<&message::From>, admins: &[i64]) -> bool {
     use tbot::types::message::From;
-    match &cmd.from {
+    match from {
         Some(from) => {
             let id = match from {
                 From::User(user) => user.id.0,
@@ -93,17 +165,21 @@ fn is_from_bot_admin(cmd: &Command, admins: &[i64]) -> bool {
     }
 }
 
-async fn is_from_chat_admin(cmd: &Command) -> bool {
+async fn is_from_chat_admin(
+    bot: &Bot,
+    chat_id: tbot::types::chat::Id,
+    from: Option<&message::From>,
+) -> bool {
     use tbot::types::message::From;
-    match &cmd.from {
+    match from {
         Some(From::User(user)) => {
-            let admins = match cmd.bot.get_chat_administrators(cmd.chat.id).call().await {
+            let admins = match bot.get_chat_administrators(chat_id).call().await {
                 Ok(r) => r,
                 _ => return false,
             };
             admins.iter().any(|member| member.user.id == user.id)
         }
-        Some(From::Chat(chat)) => chat.id == cmd.chat.id,
+        Some(From::Chat(chat)) => chat.id == chat_id,
         None => false,
     }
 }
@@ -134,6 +210,7 @@ async fn update_response(
     target: &mut MsgTarget,
     message: parameters::Text,
 ) -> Result<(), tbot::errors::MethodCall> {
+    wait_for_slot(target.chat_id.0).await;
     let msg = if target.first_time {
         bot.send_message(target.chat_id, message)
             .in_reply_to(target.message_id)
@@ -150,17 +227,53 @@ async fn update_response(
     Ok(())
 }
 
+async fn update_response_with_keyboard(
+    bot: &Bot,
+    target: &mut MsgTarget,
+    message: parameters::Text,
+    keyboard: Keyboard,
+) -> Result<(), tbot::errors::MethodCall> {
+    wait_for_slot(target.chat_id.0).await;
+    let msg = if target.first_time {
+        bot.send_message(target.chat_id, message)
+            .in_reply_to(target.message_id)
+            .reply_markup(keyboard)
+            .is_web_page_preview_disabled(true)
+            .call()
+            .await?
+    } else {
+        bot.edit_message_text(target.chat_id, target.message_id, message)
+            .reply_markup(keyboard)
+            .is_web_page_preview_disabled(true)
+            .call()
+            .await?
+    };
+    target.update(msg.id);
+    Ok(())
+}
+
+/// Channel arguments are either `@username` or a numeric chat id
+fn is_channel_arg(arg: &str) -> bool {
+    arg.starts_with('@') || arg.parse::<i64>().is_ok()
+}
+
 async fn check_channel_permission(
     cmd: &Command,
     channel: &str,
     target: &mut MsgTarget,
+) -> Result<Option<tbot::types::chat::Id>, tbot::errors::MethodCall> {
+    check_channel_permission_of(&cmd.bot, cmd.from.as_ref(), channel, target).await
+}
+
+/// Both the sender and the bot must be admins of the channel
+async fn check_channel_permission_of(
+    bot: &Bot,
+    from: Option<&message::From>,
+    channel: &str,
+    target: &mut MsgTarget,
 ) -> Result<Option<tbot::types::chat::Id>, tbot::errors::MethodCall> {
     use tbot::errors::MethodCall;
-    let bot = &cmd.bot;
-    let from = cmd
-        .from
-        .as_ref()
-        .expect("UNREACHABLE: message from channel");
+    let from = from.expect("UNREACHABLE: message from channel");
 
     if from.is_chat() {
         // FIXME: error message
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+use crate::digest::{format_schedule, parse_schedule};
+use crate::timezone::Timezone;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn digest(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let schedule;
+
+    match &*args {
+        [channel, rest @ ..] if is_channel_arg(channel) => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            schedule = rest;
+        }
+        rest => schedule = rest,
+    };
+
+    let msg = match schedule {
+        [] => match db.lock().await.chat(target_id.0).digest {
+            Some(digest) => tr!("digest_current", schedule = format_schedule(&digest)),
+            None => tr!("digest_off").to_string(),
+        },
+        ["off"] => {
+            let mut db = db.lock().await;
+            let mut chat = db.chat(target_id.0);
+            chat.digest = None;
+            db.set_chat(target_id.0, chat);
+            tr!("digest_disabled").to_string()
+        }
+        args => {
+            let mut db = db.lock().await;
+            let mut chat = db.chat(target_id.0);
+            let timezone = chat.timezone.unwrap_or(Timezone::UTC);
+            match parse_schedule(args, timezone, SystemTime::now()) {
+                Some(digest) => {
+                    chat.digest = Some(digest);
+                    db.set_chat(target_id.0, chat);
+                    tr!("digest_updated", schedule = format_schedule(&digest))
+                }
+                None => tr!("digest_how_to_use").to_string(),
+            }
+        }
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::{Database, DisplayMode};
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn display(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let mode;
+
+    match &*args {
+        [channel, rest @ ..] if is_channel_arg(channel) && rest.len() <= 1 => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            mode = rest.first();
+        }
+        [] => mode = None,
+        [m] => mode = Some(m),
+        [..] => {
+            let msg = tr!("display_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mode = match mode.map(|s| &**s) {
+        None => {
+            let current = match db.lock().await.chat(target_id.0).display_mode {
+                DisplayMode::Compact => tr!("display_mode_compact"),
+                DisplayMode::Detailed => tr!("display_mode_detailed"),
+            };
+            let msg = tr!("display_current", mode = current);
+            update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+            return Ok(());
+        }
+        Some("compact") => DisplayMode::Compact,
+        Some("detailed") => DisplayMode::Detailed,
+        Some(_) => {
+            let msg = tr!("display_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    {
+        let mut db = db.lock().await;
+        let mut chat = db.chat(target_id.0);
+        chat.display_mode = mode;
+        db.set_chat(target_id.0, chat);
+    }
+    update_response(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(tr!("display_updated")),
+    )
+    .await?;
+    Ok(())
+}
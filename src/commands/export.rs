@@ -8,6 +8,7 @@ use tokio::sync::Mutex;
 
 use crate::data::Database;
 use crate::opml::into_opml;
+use crate::sender::wait_for_slot;
 
 use super::{check_channel_permission, update_response, MsgTarget};
 
@@ -38,8 +39,9 @@ pub async fn export(
         .await?;
         return Ok(());
     }
-    let opml = into_opml(feeds.unwrap());
+    let opml = into_opml(feeds.unwrap(), target_id.0);
 
+    wait_for_slot(chat_id.0).await;
     cmd.bot
         .send_document(
             chat_id,
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+use crate::filter::Filter;
+use crate::messages::Escape;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn filter(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let feed_url;
+    let rules;
+
+    match &*args {
+        [channel, url, rest @ ..] if is_channel_arg(channel) => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed_url = url;
+            rules = rest;
+        }
+        [url, rest @ ..] if !is_channel_arg(url) => {
+            feed_url = url;
+            rules = rest;
+        }
+        [..] => {
+            let msg = tr!("filter_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut db = db.lock().await;
+    if !db.is_subscribed(target_id.0, feed_url) {
+        drop(db);
+        let msg = tr!("unsubscribed_from_rss");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    let msg = match rules {
+        [] => match db.filter(target_id.0, feed_url) {
+            Some(filter) => tr!("filter_current", filter = Escape(&filter.to_string())),
+            None => tr!("filter_empty").into(),
+        },
+        ["clear"] => {
+            db.set_filter(target_id.0, feed_url, Filter::default());
+            tr!("filter_cleared").into()
+        }
+        rules => match rules.join(" ").parse::<Filter>() {
+            Ok(filter) => {
+                let filter_str = filter.to_string();
+                db.set_filter(target_id.0, feed_url, filter);
+                tr!("filter_updated", filter = Escape(&filter_str))
+            }
+            Err(e) => tr!("filter_invalid", error = Escape(&e.to_string())),
+        },
+    };
+    drop(db);
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use futures::{stream, StreamExt};
+use tbot::{
+    contexts::{Command, Document},
+    types::{self, message, parameters},
+    Bot,
+};
+use tokio::sync::Mutex;
+
+use crate::client::pull_feed;
+use crate::data::Database;
+use crate::filter::Filter;
+use crate::i18n;
+use crate::messages::{format_large_msg, Escape};
+use crate::opml::{from_opml, Outline};
+use crate::sender::wait_for_slot;
+
+use super::{
+    check_channel_permission, check_channel_permission_of, check_chat, is_from_bot_admin,
+    remember_lang, sender_lang, update_response, MsgTarget,
+};
+
+const CONCURRENT_PULLS: usize = 8;
+
+pub async fn import(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let channel = &cmd.text.value;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    let opml = match cmd.reply_to.as_ref().map(|msg| &msg.kind) {
+        Some(message::Kind::Document(document, _)) => {
+            match download(&cmd.bot, document, target).await? {
+                Some(content) => content,
+                None => return Ok(()),
+            }
+        }
+        Some(message::Kind::Text(text)) => text.value.clone().into_bytes(),
+        _ => {
+            let msg = tr!("import_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    if !channel.is_empty() {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+    import_opml(&db, &cmd.bot, target, target_id, opml).await
+}
+
+/// Captions aren't handled as commands, so an OPML file sent with `/import` as the caption
+/// is handled here
+pub fn register_caption(
+    event_loop: &mut tbot::EventLoop,
+    opt: Arc<crate::Opt>,
+    db: Arc<Mutex<Database>>,
+) {
+    event_loop.document(move |context| {
+        let opt = opt.clone();
+        let db = db.clone();
+        async move {
+            let bot_name = crate::BOT_NAME.get().expect("BOT_NAME not initialized");
+            let channel = match caption_args(&context.caption.value, bot_name) {
+                Some(channel) => channel,
+                None => return,
+            };
+            let lang = sender_lang(&db, context.chat.id, context.from.as_ref()).await;
+            i18n::scope(lang, async {
+                // The same checks as `check_command`
+                if !opt.admin.is_empty() && !is_from_bot_admin(context.from.as_ref(), &opt.admin) {
+                    eprintln!(
+                        "Unauthenticated request from user/channel: {:?}, caption: {}",
+                        context.from, context.caption.value
+                    );
+                    return;
+                }
+                let from = context.from.as_ref();
+                if !check_chat(&opt, &context.bot, &context.chat, from, context.message_id).await {
+                    return;
+                }
+                remember_lang(&db, context.chat.id.0, lang).await;
+                if let Err(e) = import_document(&db, &context, channel).await {
+                    crate::print_error(e);
+                }
+            })
+            .await
+        }
+    });
+}
+
+/// The arguments if the caption is `/import`, or `/import@bot_name` as sent in groups
+fn caption_args<'a>(caption: &'a str, bot_name: &str) -> Option<&'a str> {
+    let caption = caption.trim_start();
+    let (command, args) = caption
+        .split_once(char::is_whitespace)
+        .unwrap_or((caption, ""));
+    let command = match command.split_once('@') {
+        Some((command, name)) if name.eq_ignore_ascii_case(bot_name) => command,
+        Some(_) => return None,
+        None => command,
+    };
+    (command == "/import").then_some(args.trim())
+}
+
+async fn import_document(
+    db: &Mutex<Database>,
+    context: &Document,
+    channel: &str,
+) -> Result<(), tbot::errors::MethodCall> {
+    let target = &mut MsgTarget::new(context.chat.id, context.message_id);
+    let opml = match download(&context.bot, &context.document, target).await? {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+    let mut target_id = context.chat.id;
+    if !channel.is_empty() {
+        let from = context.from.as_ref();
+        match check_channel_permission_of(&context.bot, from, channel, target).await? {
+            Some(channel_id) => target_id = channel_id,
+            None => return Ok(()),
+        }
+    }
+    import_opml(db, &context.bot, target, target_id, opml).await
+}
+
+/// Return `None` if the download failed, the error is shown to the user
+async fn download(
+    bot: &Bot,
+    document: &types::Document,
+    target: &mut MsgTarget,
+) -> Result<Option<Vec<u8>>, tbot::errors::MethodCall> {
+    let file = bot.get_file(document.file_id.clone()).call().await?;
+    match bot.download_file(&file).await {
+        Ok(content) => Ok(Some(content)),
+        Err(e) => {
+            let msg = tr!("import_download_failed", error = e);
+            update_response(bot, target, parameters::Text::with_plain(&msg)).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Subscribe `target_id` to the feeds, and reply the results to `target`
+async fn import_opml(
+    db: &Mutex<Database>,
+    bot: &Bot,
+    target: &mut MsgTarget,
+    target_id: types::chat::Id,
+    opml: Vec<u8>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = target.chat_id;
+
+    let outlines = match from_opml(Cursor::new(opml)) {
+        Ok(outlines) if !outlines.is_empty() => outlines,
+        Ok(_) => {
+            let msg = tr!("import_no_feeds");
+            update_response(bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            let msg = tr!("import_parsing_failed", error = e);
+            update_response(bot, target, parameters::Text::with_plain(&msg)).await?;
+            return Ok(());
+        }
+    };
+    update_response(
+        bot,
+        target,
+        parameters::Text::with_plain(tr!("processing_please_wait")),
+    )
+    .await?;
+
+    let results: Vec<String> = stream::iter(outlines)
+        .map(|outline| import_feed(db, target_id.0, outline))
+        .buffered(CONCURRENT_PULLS)
+        .collect()
+        .await;
+
+    let mut msgs = format_large_msg(tr!("import_result").to_string(), &results, |s| s.clone());
+    let first_msg = msgs.remove(0);
+    update_response(bot, target, parameters::Text::with_html(&first_msg)).await?;
+
+    let mut prev_msg = target.message_id;
+    for msg in msgs {
+        let text = parameters::Text::with_html(&msg);
+        wait_for_slot(chat_id.0).await;
+        let msg = bot
+            .send_message(chat_id, text)
+            .in_reply_to(prev_msg)
+            .is_web_page_preview_disabled(true)
+            .call()
+            .await?;
+        prev_msg = msg.id;
+    }
+    Ok(())
+}
+
+async fn import_feed(db: &Mutex<Database>, subscriber: i64, outline: Outline) -> String {
+    let title = if outline.title.is_empty() {
+        &outline.link
+    } else {
+        &outline.title
+    };
+    if db.lock().await.is_subscribed(subscriber, &outline.link) {
+        return tr!(
+            "import_feed_skipped",
+            link = Escape(&outline.link),
+            title = Escape(title)
+        );
+    }
+    if cfg!(feature = "hosted-by-iovxw") && db.lock().await.all_feeds().len() >= 1500 {
+        return tr!(
+            "import_feed_failed",
+            link = Escape(&outline.link),
+            title = Escape(title),
+            error = Escape(tr!("subscription_rate_limit_reached"))
+        );
+    }
+    match pull_feed(&outline.link).await {
+        Ok(feed) => {
+            let mut db = db.lock().await;
+            db.subscribe(subscriber, &outline.link, &feed);
+            if let Some(filter) = outline.filter.and_then(|f| f.parse::<Filter>().ok()) {
+                db.set_filter(subscriber, &outline.link, filter);
+            }
+            tr!(
+                "import_feed_succeeded",
+                link = Escape(&outline.link),
+                title = Escape(&feed.title)
+            )
+        }
+        Err(e) => tr!(
+            "import_feed_failed",
+            link = Escape(&outline.link),
+            title = Escape(title),
+            error = Escape(&e.to_user_friendly())
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn import_caption() {
+        assert_eq!(caption_args("/import", "rssbot"), Some(""));
+        assert_eq!(
+            caption_args(" /import  @channel ", "rssbot"),
+            Some("@channel")
+        );
+        assert_eq!(
+            caption_args("/import@RSSBot -100123", "rssbot"),
+            Some("-100123")
+        );
+        assert_eq!(caption_args("/import@other_bot", "rssbot"), None);
+        assert_eq!(caption_args("/imports", "rssbot"), None);
+        assert_eq!(caption_args("my feeds", "rssbot"), None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+use crate::i18n::{self, Lang};
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn lang(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let code;
+
+    match &*args {
+        [channel, rest @ ..] if is_channel_arg(channel) && rest.len() <= 1 => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            code = rest.first();
+        }
+        [] => code = None,
+        [c] => code = Some(c),
+        [..] => {
+            let msg = tr!("lang_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let langs = Lang::all()
+        .map(|lang| lang.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut db = db.lock().await;
+    let mut chat = db.chat(target_id.0);
+    let msg = match code.map(|s| &**s) {
+        None => tr!("lang_current", lang = chat.lang(), langs = langs),
+        Some("auto") => {
+            chat.language = None;
+            db.set_chat(target_id.0, chat);
+            tr!("lang_auto").to_string()
+        }
+        Some(code) => match Lang::find(code) {
+            Some(lang) => {
+                chat.language = Some(lang.code().to_string());
+                db.set_chat(target_id.0, chat);
+                // Reply in the new language, unless a channel is changed
+                if target_id == chat_id {
+                    i18n::with(lang, || tr!("lang_updated", lang = lang))
+                } else {
+                    tr!("lang_updated", lang = lang)
+                }
+            }
+            None => tr!("lang_unknown", lang = code, langs = langs),
+        },
+    };
+    drop(db);
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tbot::{
+    contexts::{methods::Callback, DataCallback},
+    types::{
+        callback,
+        keyboard::inline::{Button, ButtonKind, Keyboard},
+        parameters,
+    },
+};
+use tokio::sync::Mutex;
+
+use crate::data::{feed_id, Database, Feed, FeedId};
+use crate::i18n::{self, Lang};
+use crate::sender::wait_for_slot;
+
+use super::rss::sort_feeds;
+use super::settings::{render_settings, Setting};
+
+const PAGE_SIZE: usize = 8;
+const BUTTON_MAX_CHARS: usize = 48;
+
+/// Callback data of the buttons, Telegram limits it to 64 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Page {
+        chat: i64,
+        page: usize,
+    },
+    Unsub {
+        chat: i64,
+        page: usize,
+        feed: FeedId,
+    },
+    Settings {
+        chat: i64,
+        setting: Setting,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Page { chat, page } => write!(f, "page:{}:{}", chat, page),
+            Action::Unsub { chat, page, feed } => {
+                write!(f, "unsub:{}:{}:{:016x}", chat, page, feed)
+            }
+            Action::Settings { chat, setting } => write!(f, "set:{}:{}", chat, setting),
+        }
+    }
+}
+
+impl Action {
+    fn parse(data: &str) -> Option<Action> {
+        let parts = data.split(':').collect::<Vec<_>>();
+        match &*parts {
+            ["page", chat, page] => Some(Action::Page {
+                chat: chat.parse().ok()?,
+                page: page.parse().ok()?,
+            }),
+            ["unsub", chat, page, feed] => Some(Action::Unsub {
+                chat: chat.parse().ok()?,
+                page: page.parse().ok()?,
+                feed: FeedId::from_str_radix(feed, 16).ok()?,
+            }),
+            ["set", chat, key, value] => Some(Action::Settings {
+                chat: chat.parse().ok()?,
+                setting: Setting::parse(key, value)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn chat(&self) -> i64 {
+        match *self {
+            Action::Page { chat, .. }
+            | Action::Unsub { chat, .. }
+            | Action::Settings { chat, .. } => chat,
+        }
+    }
+}
+
+/// A button that opens the first page of the keyboard
+pub fn manage_button(chat: i64) -> Keyboard {
+    let action = Action::Page { chat, page: 0 };
+    Keyboard::new(vec![vec![Button::new(
+        tr!("manage_subscriptions"),
+        ButtonKind::CallbackData(action.to_string()),
+    )]])
+}
+
+/// The message and keyboard of a page of subscriptions, tap a feed to unsubscribe
+pub fn render_page(feeds: &[Feed], chat: i64, page: usize) -> (String, Keyboard) {
+    if feeds.is_empty() {
+        return (
+            tr!("subscription_list_empty").to_string(),
+            Keyboard::new(Vec::new()),
+        );
+    }
+    let pages = (feeds.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let page = page.min(pages - 1);
+    let mut rows: Vec<Vec<Button>> = feeds
+        .iter()
+        .skip(page * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|feed| {
+            let mut title: String = feed.title.chars().take(BUTTON_MAX_CHARS).collect();
+            if title.len() < feed.title.len() {
+                title.push('…');
+            }
+            let action = Action::Unsub {
+                chat,
+                page,
+                feed: feed_id(&feed.link),
+            };
+            vec![Button::new(
+                title,
+                ButtonKind::CallbackData(action.to_string()),
+            )]
+        })
+        .collect();
+    if pages > 1 {
+        let mut nav = Vec::new();
+        if page > 0 {
+            let action = Action::Page {
+                chat,
+                page: page - 1,
+            };
+            nav.push(Button::new(
+                "‹",
+                ButtonKind::CallbackData(action.to_string()),
+            ));
+        }
+        if page + 1 < pages {
+            let action = Action::Page {
+                chat,
+                page: page + 1,
+            };
+            nav.push(Button::new(
+                "›",
+                ButtonKind::CallbackData(action.to_string()),
+            ));
+        }
+        rows.push(nav);
+    }
+    let msg = tr!("unsub_choose", page = page + 1, pages = pages);
+    (msg, Keyboard::new(rows))
+}
+
+pub fn register_callback(
+    event_loop: &mut tbot::EventLoop,
+    opt: Arc<crate::Opt>,
+    db: Arc<Mutex<Database>>,
+) {
+    event_loop.data_callback(move |context| {
+        let opt = opt.clone();
+        let db = db.clone();
+        async move {
+            let lang = callback_lang(&db, &context).await;
+            if let Err(e) = i18n::scope(lang, handle_callback(&opt, &db, &context)).await {
+                crate::print_error(e);
+            }
+        }
+    });
+}
+
+/// The language of the chat with the keyboard, or else of the user
+async fn callback_lang(db: &Mutex<Database>, context: &DataCallback) -> Lang {
+    let chat = match &context.origin {
+        callback::Origin::Message(message) => db.lock().await.chat(message.chat.id.0),
+        _ => Default::default(),
+    };
+    chat.lang_or(context.from.language_code.as_deref())
+}
+
+async fn handle_callback(
+    opt: &crate::Opt,
+    db: &Mutex<Database>,
+    context: &DataCallback,
+) -> Result<(), tbot::errors::MethodCall> {
+    let message = match &context.origin {
+        callback::Origin::Message(message) => message,
+        _ => return Ok(()),
+    };
+    let action = match Action::parse(&context.data) {
+        Some(action) => action,
+        None => {
+            context.ignore().call().await?;
+            return Ok(());
+        }
+    };
+    // The keyboard could be forwarded, check the permission again
+    if !callback_is_permitted(opt, context, message.chat.id, action.chat()).await {
+        context
+            .alert(tr!("callback_permission_denied"))
+            .call()
+            .await?;
+        return Ok(());
+    }
+
+    let (msg, keyboard, notification) = match action {
+        Action::Page { chat, page } => {
+            let (msg, keyboard) = subscriptions_page(db, chat, page).await;
+            (msg, keyboard, None)
+        }
+        Action::Unsub { chat, page, feed } => {
+            let unsubscribed = {
+                let mut db = db.lock().await;
+                let link = db
+                    .subscribed_feeds(chat)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|f| feed_id(&f.link) == feed)
+                    .map(|f| f.link);
+                link.and_then(|link| db.unsubscribe(chat, &link))
+            };
+            let notification = match unsubscribed {
+                Some(feed) => tr!("unsub_done", title = feed.title),
+                None => tr!("unsubscribed_from_rss").to_string(),
+            };
+            let (msg, keyboard) = subscriptions_page(db, chat, page).await;
+            (msg, keyboard, Some(notification))
+        }
+        Action::Settings { chat, setting } => {
+            let mut db = db.lock().await;
+            let mut settings = db.chat(chat);
+            setting.apply(&mut settings);
+            db.set_chat(chat, settings.clone());
+            // Show the new language right away if it's this chat's
+            let lang = if chat == message.chat.id.0 {
+                settings.lang_or(context.from.language_code.as_deref())
+            } else {
+                i18n::current()
+            };
+            let (msg, keyboard) = i18n::with(lang, || render_settings(&settings, chat));
+            (msg, keyboard, None)
+        }
+    };
+
+    wait_for_slot(message.chat.id.0).await;
+    context
+        .bot
+        .edit_message_text(
+            message.chat.id,
+            message.id,
+            parameters::Text::with_plain(&msg),
+        )
+        .reply_markup(keyboard)
+        .call()
+        .await?;
+    match notification {
+        Some(text) => context.notify(text).call().await?,
+        None => context.ignore().call().await?,
+    }
+    Ok(())
+}
+
+async fn subscriptions_page(db: &Mutex<Database>, chat: i64, page: usize) -> (String, Keyboard) {
+    let mut feeds = db.lock().await.subscribed_feeds(chat).unwrap_or_default();
+    sort_feeds(&mut feeds);
+    render_page(&feeds, chat, page)
+}
+
+/// The same rules as commands: private mode, restricted mode, and channel admins
+async fn callback_is_permitted(
+    opt: &crate::Opt,
+    context: &DataCallback,
+    chat_id: tbot::types::chat::Id,
+    target: i64,
+) -> bool {
+    let user_id = context.from.id;
+    if !opt.admin.is_empty() && !opt.admin.contains(&user_id.0) {
+        return false;
+    }
+    let bot = &context.bot;
+    let is_admin_of = |chat: tbot::types::chat::Id| async move {
+        match bot.get_chat_administrators(chat).call().await {
+            Ok(admins) => admins.iter().any(|member| member.user.id == user_id),
+            Err(_) => false,
+        }
+    };
+    if target != chat_id.0 {
+        // A channel managed from this chat
+        return is_admin_of(tbot::types::chat::Id(target)).await;
+    }
+    if opt.restricted && chat_id.0 < 0 {
+        return is_admin_of(chat_id).await;
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn callback_data() {
+        let actions = [
+            Action::Page {
+                chat: -1001234567890,
+                page: 3,
+            },
+            Action::Unsub {
+                chat: -1001234567890,
+                page: 999,
+                feed: u64::MAX,
+            },
+            Action::Settings {
+                chat: -1001234567890,
+                setting: Setting::UtcOffset(-12 * 3600),
+            },
+        ];
+        for action in actions {
+            let data = action.to_string();
+            assert!(data.len() <= 64);
+            assert_eq!(Action::parse(&data), Some(action));
+        }
+        assert_eq!(Action::parse("unsub:1:0:xyz"), None);
+    }
+}
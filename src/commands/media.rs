@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn media(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let feed_url;
+    let switch;
+
+    match &*args {
+        [channel, url, rest @ ..] if is_channel_arg(channel) && rest.len() <= 1 => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed_url = url;
+            switch = rest.first();
+        }
+        [url, rest @ ..] if !is_channel_arg(url) && rest.len() <= 1 => {
+            feed_url = url;
+            switch = rest.first();
+        }
+        [..] => {
+            let msg = tr!("media_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut db = db.lock().await;
+    if !db.is_subscribed(target_id.0, feed_url) {
+        drop(db);
+        let msg = tr!("unsubscribed_from_rss");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    let msg = match switch.map(|s| &**s) {
+        None if db.media_enabled(target_id.0, feed_url) => tr!("media_on"),
+        None => tr!("media_off"),
+        Some("on") => {
+            db.set_media(target_id.0, feed_url, true);
+            tr!("media_enabled")
+        }
+        Some("off") => {
+            db.set_media(target_id.0, feed_url, false);
+            tr!("media_disabled")
+        }
+        Some(_) => tr!("media_how_to_use"),
+    };
+    drop(db);
+    update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+    Ok(())
+}
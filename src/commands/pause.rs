@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn pause(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    set_paused(db, cmd, true).await
+}
+
+/// Shared by /pause and /resume, without an URL all subscriptions are changed
+pub(super) async fn set_paused(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+    paused: bool,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let feed_url;
+
+    match &*args {
+        [] => feed_url = None,
+        [channel, rest @ ..] if is_channel_arg(channel) && rest.len() <= 1 => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed_url = rest.first();
+        }
+        [url] => feed_url = Some(url),
+        [..] => {
+            let msg = if paused {
+                tr!("pause_how_to_use")
+            } else {
+                tr!("resume_how_to_use")
+            };
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let msg = {
+        let mut db = db.lock().await;
+        match feed_url {
+            Some(url) if !db.is_subscribed(target_id.0, url) => {
+                tr!("unsubscribed_from_rss").to_string()
+            }
+            _ => {
+                let count = db.set_paused(target_id.0, feed_url.copied(), paused);
+                if paused {
+                    tr!("pause_succeeded", count = count)
+                } else {
+                    tr!("resume_succeeded", count = count)
+                }
+            }
+        }
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
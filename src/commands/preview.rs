@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn preview(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let feed_url;
+    let switch;
+
+    match &*args {
+        [channel, url, rest @ ..] if is_channel_arg(channel) && rest.len() <= 1 => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            feed_url = url;
+            switch = rest.first();
+        }
+        [url, rest @ ..] if !is_channel_arg(url) && rest.len() <= 1 => {
+            feed_url = url;
+            switch = rest.first();
+        }
+        [..] => {
+            let msg = tr!("preview_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut db = db.lock().await;
+    if !db.is_subscribed(target_id.0, feed_url) {
+        drop(db);
+        let msg = tr!("unsubscribed_from_rss");
+        update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+        return Ok(());
+    }
+    let link_preview = match switch.map(|s| &**s) {
+        None => {
+            let msg = match db.link_preview(target_id.0, feed_url) {
+                Some(true) => tr!("preview_on"),
+                Some(false) => tr!("preview_off"),
+                None if db.chat(target_id.0).link_preview => tr!("preview_default_on"),
+                None => tr!("preview_default_off"),
+            };
+            drop(db);
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        Some("default") => None,
+        Some(_) => {
+            drop(db);
+            let msg = tr!("preview_how_to_use");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    };
+    db.set_link_preview(target_id.0, feed_url, link_preview);
+    drop(db);
+    let msg = tr!("preview_updated");
+    update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+use crate::quiet::{format_quiet_hours, parse_quiet_hours};
+use crate::timezone::Timezone;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn quiet(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let hours;
+
+    match &*args {
+        [channel, rest @ ..] if is_channel_arg(channel) => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            hours = rest;
+        }
+        rest => hours = rest,
+    };
+
+    let msg = match hours {
+        [] => match db.lock().await.chat(target_id.0).quiet_hours {
+            Some(quiet) => tr!("quiet_current", hours = format_quiet_hours(&quiet)),
+            None => tr!("quiet_off").to_string(),
+        },
+        ["off"] => {
+            let mut db = db.lock().await;
+            let mut chat = db.chat(target_id.0);
+            chat.quiet_hours = None;
+            db.set_chat(target_id.0, chat);
+            tr!("quiet_disabled").to_string()
+        }
+        args => {
+            let mut db = db.lock().await;
+            let mut chat = db.chat(target_id.0);
+            match parse_quiet_hours(args, chat.timezone.unwrap_or(Timezone::UTC)) {
+                Some(quiet) => {
+                    chat.quiet_hours = Some(quiet);
+                    db.set_chat(target_id.0, chat);
+                    tr!("quiet_updated", hours = format_quiet_hours(&quiet))
+                }
+                None => tr!("quiet_how_to_use").to_string(),
+            }
+        }
+    };
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
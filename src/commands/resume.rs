@@ -0,0 +1,15 @@
+use std::sync::Arc;
+
+use tbot::contexts::Command;
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+
+use super::pause::set_paused;
+
+pub async fn resume(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    set_paused(db, cmd, false).await
+}
@@ -5,10 +5,12 @@ use pinyin::{Pinyin, ToPinyin};
 use tbot::{contexts::Command, types::parameters};
 use tokio::sync::Mutex;
 
-use crate::data::Database;
+use crate::data::{Database, Feed};
 use crate::messages::{format_large_msg, Escape};
+use crate::sender::wait_for_slot;
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::manage::manage_button;
+use super::{check_channel_permission, update_response, update_response_with_keyboard, MsgTarget};
 
 pub async fn rss(
     db: Arc<Mutex<Database>>,
@@ -28,35 +30,48 @@ pub async fn rss(
     }
 
     let feeds = db.lock().await.subscribed_feeds(target_id.0);
+    let has_feeds = feeds.is_some();
     let mut msgs = if let Some(mut feeds) = feeds {
-        feeds.sort_by_cached_key(|feed| {
-            feed.title
-                .chars()
-                .map(|c| {
-                    c.to_pinyin()
-                        .map(Pinyin::plain)
-                        .map(Either::Right)
-                        .unwrap_or_else(|| Either::Left(c))
-                })
-                .collect::<Vec<Either<char, &str>>>()
-        });
+        sort_feeds(&mut feeds);
         format_large_msg(tr!("subscription_list").to_string(), &feeds, |feed| {
-            format!(
+            let line = format!(
                 "<a href=\"{}\">{}</a>",
                 Escape(&feed.link),
                 Escape(&feed.title)
-            )
+            );
+            let line = match feed.filters.get(&target_id.0) {
+                Some(filter) => format!("{} <code>{}</code>", line, Escape(&filter.to_string())),
+                None => line,
+            };
+            if feed.paused.contains(&target_id.0) {
+                format!("{} {}", line, tr!("subscription_paused"))
+            } else {
+                line
+            }
         })
     } else {
         vec![tr!("subscription_list_empty").to_string()]
     };
 
+    // The button to manage subscriptions is attached to the last message
+    let last_msg = msgs.pop().unwrap();
+    if msgs.is_empty() {
+        let text = parameters::Text::with_html(&last_msg);
+        if has_feeds {
+            update_response_with_keyboard(&cmd.bot, target, text, manage_button(target_id.0))
+                .await?;
+        } else {
+            update_response(&cmd.bot, target, text).await?;
+        }
+        return Ok(());
+    }
     let first_msg = msgs.remove(0);
     update_response(&cmd.bot, target, parameters::Text::with_html(&first_msg)).await?;
 
     let mut prev_msg = target.message_id;
     for msg in msgs {
         let text = parameters::Text::with_html(&msg);
+        wait_for_slot(chat_id.0).await;
         let msg = cmd
             .bot
             .send_message(chat_id, text)
@@ -66,5 +81,28 @@ pub async fn rss(
             .await?;
         prev_msg = msg.id;
     }
+    wait_for_slot(chat_id.0).await;
+    cmd.bot
+        .send_message(chat_id, parameters::Text::with_html(&last_msg))
+        .in_reply_to(prev_msg)
+        .reply_markup(manage_button(target_id.0))
+        .is_web_page_preview_disabled(true)
+        .call()
+        .await?;
     Ok(())
 }
+
+/// Sort by title, Chinese characters are sorted by pinyin
+pub fn sort_feeds(feeds: &mut [Feed]) {
+    feeds.sort_by_cached_key(|feed| {
+        feed.title
+            .chars()
+            .map(|c| {
+                c.to_pinyin()
+                    .map(Pinyin::plain)
+                    .map(Either::Right)
+                    .unwrap_or_else(|| Either::Left(c))
+            })
+            .collect::<Vec<Either<char, &str>>>()
+    });
+}
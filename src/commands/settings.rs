@@ -0,0 +1,289 @@
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::Utc;
+use tbot::{
+    contexts::Command,
+    types::{
+        keyboard::inline::{Button, ButtonKind, Keyboard},
+        parameters,
+    },
+};
+use tokio::sync::Mutex;
+
+use crate::data::{Chat, Database, DisplayMode};
+use crate::i18n::Lang;
+use crate::timezone::{format_utc_offset, Timezone};
+
+use super::manage::Action;
+use super::{
+    check_channel_permission, is_channel_arg, update_response, update_response_with_keyboard,
+    MsgTarget,
+};
+
+/// `parse_utc_offset` accepts up to 14 hours
+const MAX_UTC_OFFSET: i32 = 14 * 3600;
+
+/// A button of the settings keyboard, it carries the new value,
+/// so tapping an outdated keyboard doesn't toggle back and forth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// `None` follows the Telegram language of the user
+    Language(Option<Lang>),
+    UtcOffset(i32),
+    DisplayMode(DisplayMode),
+    Silent(bool),
+    LinkPreview(bool),
+    EditMessages(bool),
+    ClearFilter,
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Setting::Language(Some(lang)) => write!(f, "lang:{}", lang.code()),
+            Setting::Language(None) => write!(f, "lang:auto"),
+            Setting::UtcOffset(offset) => write!(f, "tz:{}", offset),
+            Setting::DisplayMode(DisplayMode::Compact) => write!(f, "display:compact"),
+            Setting::DisplayMode(DisplayMode::Detailed) => write!(f, "display:detailed"),
+            Setting::Silent(silent) => write!(f, "silent:{}", silent as u8),
+            Setting::LinkPreview(preview) => write!(f, "preview:{}", preview as u8),
+            Setting::EditMessages(edit) => write!(f, "edit:{}", edit as u8),
+            Setting::ClearFilter => write!(f, "filter:clear"),
+        }
+    }
+}
+
+impl Setting {
+    pub fn parse(key: &str, value: &str) -> Option<Setting> {
+        let flag = |value: &str| match value {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        };
+        match (key, value) {
+            ("lang", "auto") => Some(Setting::Language(None)),
+            ("lang", code) => Lang::find(code).map(|lang| Setting::Language(Some(lang))),
+            ("tz", offset) => offset
+                .parse()
+                .ok()
+                .filter(|offset: &i32| offset.abs() <= MAX_UTC_OFFSET)
+                .map(Setting::UtcOffset),
+            ("display", "compact") => Some(Setting::DisplayMode(DisplayMode::Compact)),
+            ("display", "detailed") => Some(Setting::DisplayMode(DisplayMode::Detailed)),
+            ("silent", value) => flag(value).map(Setting::Silent),
+            ("preview", value) => flag(value).map(Setting::LinkPreview),
+            ("edit", value) => flag(value).map(Setting::EditMessages),
+            ("filter", "clear") => Some(Setting::ClearFilter),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, chat: &mut Chat) {
+        match self {
+            Setting::Language(lang) => chat.language = lang.map(|lang| lang.code().to_string()),
+            Setting::UtcOffset(offset) => chat.timezone = Some(Timezone::Fixed(offset)),
+            Setting::DisplayMode(mode) => chat.display_mode = mode,
+            Setting::Silent(silent) => chat.silent = silent,
+            Setting::LinkPreview(preview) => chat.link_preview = preview,
+            Setting::EditMessages(edit) => chat.edit_messages = edit,
+            Setting::ClearFilter => chat.filter = None,
+        }
+    }
+}
+
+/// The settings of a chat, with a keyboard to change them
+pub fn render_settings(settings: &Chat, chat: i64) -> (String, Keyboard) {
+    let on_off = |on: bool| {
+        if on {
+            tr!("settings_on")
+        } else {
+            tr!("settings_off")
+        }
+    };
+    let language = match settings.language {
+        Some(_) => settings.lang().to_string(),
+        None => tr!("settings_auto").to_string(),
+    };
+    let timezone = match settings.timezone {
+        Some(timezone) => timezone.to_string(),
+        None => tr!("settings_not_set").to_string(),
+    };
+    let display_mode = match settings.display_mode {
+        DisplayMode::Compact => tr!("settings_compact"),
+        DisplayMode::Detailed => tr!("settings_detailed"),
+    };
+    let filter = match &settings.filter {
+        Some(filter) => filter.to_string(),
+        None => tr!("settings_not_set").to_string(),
+    };
+    let msg = tr!(
+        "settings_current",
+        language = language,
+        timezone = timezone,
+        display_mode = display_mode,
+        silent = on_off(settings.silent),
+        link_preview = on_off(settings.link_preview),
+        edit_messages = on_off(settings.edit_messages),
+        filter = filter
+    );
+
+    let button = |text: String, setting: Setting| {
+        let action = Action::Settings { chat, setting };
+        Button::new(text, ButtonKind::CallbackData(action.to_string()))
+    };
+    // Auto, then every language in turn
+    let next_lang = match settings.language {
+        Some(_) => Lang::all()
+            .skip_while(|lang| *lang != settings.lang())
+            .nth(1),
+        None => Lang::all().next(),
+    };
+    // A zone name steps from its current offset to fixed ones
+    let offset = settings.timezone.map_or(0, |timezone| {
+        timezone.offset_at(Utc::now()).local_minus_utc()
+    });
+    let mut timezones = Vec::new();
+    if offset - 3600 >= -MAX_UTC_OFFSET {
+        let text = format!("‹ {}", format_utc_offset(offset - 3600));
+        timezones.push(button(text, Setting::UtcOffset(offset - 3600)));
+    }
+    if offset + 3600 <= MAX_UTC_OFFSET {
+        let text = format!("{} ›", format_utc_offset(offset + 3600));
+        timezones.push(button(text, Setting::UtcOffset(offset + 3600)));
+    }
+    let next_display_mode = match settings.display_mode {
+        DisplayMode::Compact => DisplayMode::Detailed,
+        DisplayMode::Detailed => DisplayMode::Compact,
+    };
+    let mut rows = vec![
+        vec![button(
+            format!("{}: {}", tr!("settings_language"), language),
+            Setting::Language(next_lang),
+        )],
+        timezones,
+        vec![button(
+            format!("{}: {}", tr!("settings_display_mode"), display_mode),
+            Setting::DisplayMode(next_display_mode),
+        )],
+        vec![button(
+            format!("{}: {}", tr!("settings_silent"), on_off(settings.silent)),
+            Setting::Silent(!settings.silent),
+        )],
+        vec![button(
+            format!(
+                "{}: {}",
+                tr!("settings_link_preview"),
+                on_off(settings.link_preview)
+            ),
+            Setting::LinkPreview(!settings.link_preview),
+        )],
+        vec![button(
+            format!(
+                "{}: {}",
+                tr!("settings_edit_messages"),
+                on_off(settings.edit_messages)
+            ),
+            Setting::EditMessages(!settings.edit_messages),
+        )],
+    ];
+    if settings.filter.is_some() {
+        rows.push(vec![button(
+            tr!("settings_clear_filter").to_string(),
+            Setting::ClearFilter,
+        )]);
+    }
+    (msg, Keyboard::new(rows))
+}
+
+pub async fn settings(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let text = &cmd.text.value;
+    let args = text.split_whitespace().collect::<Vec<_>>();
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+    let change;
+
+    match &*args {
+        [channel, rest @ ..] if is_channel_arg(channel) => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            target_id = channel_id.unwrap();
+            change = rest;
+        }
+        rest => change = rest,
+    };
+
+    let mut db = db.lock().await;
+    let mut chat = db.chat(target_id.0);
+    let msg = match change {
+        [] => {
+            let (msg, keyboard) = render_settings(&chat, target_id.0);
+            drop(db);
+            let text = parameters::Text::with_plain(&msg);
+            update_response_with_keyboard(&cmd.bot, target, text, keyboard).await?;
+            return Ok(());
+        }
+        ["timezone", "off"] => {
+            chat.timezone = None;
+            db.set_chat(target_id.0, chat);
+            tr!("settings_updated").to_string()
+        }
+        ["timezone", timezone] => match timezone.parse() {
+            Ok(timezone) => {
+                chat.timezone = Some(timezone);
+                db.set_chat(target_id.0, chat);
+                tr!("settings_updated").to_string()
+            }
+            Err(()) => tr!("settings_how_to_use").to_string(),
+        },
+        ["filter", "off"] => {
+            chat.filter = None;
+            db.set_chat(target_id.0, chat);
+            tr!("settings_updated").to_string()
+        }
+        ["filter", rules @ ..] if !rules.is_empty() => match rules.join(" ").parse() {
+            Ok(filter) => {
+                chat.filter = Some(filter);
+                db.set_chat(target_id.0, chat);
+                tr!("settings_updated").to_string()
+            }
+            Err(e) => tr!("filter_invalid", error = e),
+        },
+        _ => tr!("settings_how_to_use").to_string(),
+    };
+    drop(db);
+    update_response(&cmd.bot, target, parameters::Text::with_plain(&msg)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn setting_data() {
+        let settings = [
+            Setting::Language(Lang::find("en")),
+            Setting::Language(None),
+            Setting::UtcOffset(-MAX_UTC_OFFSET),
+            Setting::DisplayMode(DisplayMode::Detailed),
+            Setting::Silent(true),
+            Setting::LinkPreview(false),
+            Setting::EditMessages(true),
+            Setting::ClearFilter,
+        ];
+        for setting in settings {
+            let data = setting.to_string();
+            let (key, value) = data.split_once(':').unwrap();
+            assert_eq!(Setting::parse(key, value), Some(setting));
+        }
+        assert_eq!(Setting::parse("tz", "86400"), None);
+        assert_eq!(Setting::parse("silent", "yes"), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::{Database, Feed};
+use crate::fetcher::{fetch_interval, next_fetch};
+use crate::messages::{format_large_msg, Escape};
+use crate::sender::wait_for_slot;
+
+use super::{check_channel_permission, update_response, MsgTarget};
+
+pub async fn status(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let channel = &cmd.text.value;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    if !channel.is_empty() {
+        let channel_id = check_channel_permission(&cmd, channel, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+    }
+
+    let (mut feeds, delivery_failure) = {
+        let db = db.lock().await;
+        let feeds = db.subscribed_feeds(target_id.0).unwrap_or_default();
+        (feeds, db.delivery_failure(target_id.0).cloned())
+    };
+    let now = SystemTime::now();
+    let postponed_until = |feed: &Feed| feed.retry_after.filter(|t| *t > now);
+    feeds.retain(|feed| feed.error_count != 0 || postponed_until(feed).is_some());
+    let mut msgs = if feeds.is_empty() && delivery_failure.is_none() {
+        vec![tr!("status_all_ok").to_string()]
+    } else {
+        let mut header = delivery_failure
+            .map(|failure| {
+                tr!(
+                    "status_delivery_failing",
+                    count = failure.count,
+                    since = format_time(failure.since),
+                    error = Escape(&failure.last_error)
+                )
+            })
+            .unwrap_or_default();
+        if !feeds.is_empty() {
+            if !header.is_empty() {
+                header.push_str("\n\n");
+            }
+            header.push_str(tr!("status_list"));
+        }
+        feeds.sort_by_key(|feed| std::cmp::Reverse(feed.error_count));
+        format_large_msg(header, &feeds, |feed| {
+            if let Some(until) = postponed_until(feed) {
+                return tr!(
+                    "status_feed_postponed",
+                    link = Escape(&feed.link),
+                    title = Escape(&feed.title),
+                    until = format_time(until)
+                );
+            }
+            let since = feed.down_time.map(format_time).unwrap_or_default();
+            // Not queued while it's being fetched
+            let next = next_fetch(&feed.link).unwrap_or(now);
+            tr!(
+                "status_feed_failing",
+                link = Escape(&feed.link),
+                title = Escape(&feed.title),
+                count = feed.error_count,
+                since = since,
+                interval = format_interval(fetch_interval(feed)),
+                next = format_time(next),
+                error = Escape(feed.last_error.as_deref().unwrap_or_default())
+            )
+        })
+    };
+
+    let first_msg = msgs.remove(0);
+    update_response(&cmd.bot, target, parameters::Text::with_html(&first_msg)).await?;
+
+    let mut prev_msg = target.message_id;
+    for msg in msgs {
+        let text = parameters::Text::with_html(&msg);
+        wait_for_slot(chat_id.0).await;
+        let msg = cmd
+            .bot
+            .send_message(chat_id, text)
+            .in_reply_to(prev_msg)
+            .is_web_page_preview_disabled(true)
+            .call()
+            .await?;
+        prev_msg = msg.id;
+    }
+    Ok(())
+}
+
+/// Like `2h 8m`
+fn format_interval(interval: Duration) -> String {
+    let minutes = interval.as_secs() / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, m) => format!("{}m", m),
+        (h, 0) => format!("{}h", h),
+        (h, m) => format!("{}h {}m", h, m),
+    }
+}
+
+fn format_time(t: SystemTime) -> String {
+    DateTime::<Utc>::from(t)
+        .format("%Y-%m-%d %H:%M UTC")
+        .to_string()
+}
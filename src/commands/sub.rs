@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tbot::{contexts::Command, types::parameters};
 use tokio::sync::Mutex;
 
-use crate::client::pull_feed;
+use crate::client::{pull_feed, FeedError};
 use crate::data::Database;
 use crate::messages::Escape;
 
@@ -57,9 +57,39 @@ pub async fn sub(
         parameters::Text::with_plain(tr!("processing_please_wait")),
     )
     .await?;
-    let msg = match pull_feed(feed_url).await {
+    let mut feed_url = feed_url.to_string();
+    let mut result = pull_feed(&feed_url).await;
+    // A web page, try the feeds it links to
+    if let Err(FeedError::Html(feeds)) = &result {
+        match &feeds.clone()[..] {
+            [] => (),
+            [link] => {
+                feed_url = link.clone();
+                if db.lock().await.is_subscribed(target_id.0, &feed_url) {
+                    update_response(
+                        &cmd.bot,
+                        target,
+                        parameters::Text::with_plain(tr!("subscribed_to_rss")),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                result = pull_feed(&feed_url).await;
+            }
+            links => {
+                let list: Vec<String> = links
+                    .iter()
+                    .map(|link| format!("<code>{}</code>", Escape(link)))
+                    .collect();
+                let msg = format!("{}\n{}", tr!("sub_multiple_feeds_found"), list.join("\n"));
+                update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+                return Ok(());
+            }
+        }
+    }
+    let msg = match result {
         Ok(feed) => {
-            if db.lock().await.subscribe(target_id.0, feed_url, &feed) {
+            if db.lock().await.subscribe(target_id.0, &feed_url, &feed) {
                 tr!(
                     "subscription_succeeded",
                     link = Escape(&feed.link),
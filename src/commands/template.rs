@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use tbot::{contexts::Command, types::parameters};
+use tokio::sync::Mutex;
+
+use crate::data::Database;
+use crate::messages::Escape;
+use crate::template::Template;
+
+use super::{check_channel_permission, is_channel_arg, update_response, MsgTarget};
+
+pub async fn template(
+    db: Arc<Mutex<Database>>,
+    cmd: Arc<Command>,
+) -> Result<(), tbot::errors::MethodCall> {
+    let chat_id = cmd.chat.id;
+    let mut target_id = chat_id;
+    let target = &mut MsgTarget::new(chat_id, cmd.message_id);
+
+    // The template may contain spaces and newlines, only split off the leading arguments
+    let mut rest = cmd.text.value.as_str();
+    let (word, after) = split_word(rest);
+    if is_channel_arg(word) {
+        let channel_id = check_channel_permission(&cmd, word, target).await?;
+        if channel_id.is_none() {
+            return Ok(());
+        }
+        target_id = channel_id.unwrap();
+        rest = after;
+    }
+    let (word, after) = split_word(rest);
+    let feed_url = if word.starts_with("http://") || word.starts_with("https://") {
+        rest = after;
+        Some(word)
+    } else {
+        None
+    };
+    let rest = rest.trim();
+
+    let mut db = db.lock().await;
+    if let Some(url) = feed_url {
+        if !db.is_subscribed(target_id.0, url) {
+            drop(db);
+            let msg = tr!("unsubscribed_from_rss");
+            update_response(&cmd.bot, target, parameters::Text::with_plain(msg)).await?;
+            return Ok(());
+        }
+    }
+    let msg = match rest {
+        "" => {
+            let current = match feed_url {
+                Some(url) => db.template(target_id.0, url).cloned(),
+                None => db.chat(target_id.0).template,
+            };
+            match current {
+                Some(template) => tr!("template_current", template = Escape(&template.to_string())),
+                None => format!(
+                    "{}\n{}",
+                    tr!("template_empty"),
+                    Escape(tr!("template_how_to_use"))
+                ),
+            }
+        }
+        "clear" => {
+            set_template(&mut db, target_id.0, feed_url, None);
+            tr!("template_cleared").to_string()
+        }
+        source => match source.parse::<Template>() {
+            Ok(template) => {
+                set_template(&mut db, target_id.0, feed_url, Some(template));
+                tr!("template_updated").to_string()
+            }
+            Err(e) => tr!("template_invalid", error = Escape(&e.to_string())),
+        },
+    };
+    drop(db);
+    update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
+    Ok(())
+}
+
+/// Per subscription if `feed_url` is set, otherwise per chat
+fn set_template(
+    db: &mut Database,
+    subscriber: i64,
+    feed_url: Option<&str>,
+    template: Option<Template>,
+) {
+    match feed_url {
+        Some(url) => {
+            db.set_template(subscriber, url, template);
+        }
+        None => {
+            let mut chat = db.chat(subscriber);
+            chat.template = template;
+            db.set_chat(subscriber, chat);
+        }
+    }
+}
+
+/// Split off the first word, the rest is kept as is
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(pos) => (&s[..pos], &s[pos..]),
+        None => (s, ""),
+    }
+}
@@ -6,7 +6,12 @@ use tokio::sync::Mutex;
 use crate::data::Database;
 use crate::messages::Escape;
 
-use super::{check_channel_permission, update_response, MsgTarget};
+use super::manage::render_page;
+use super::rss::sort_feeds;
+use super::{
+    check_channel_permission, is_channel_arg, update_response, update_response_with_keyboard,
+    MsgTarget,
+};
 
 pub async fn unsub(
     db: Arc<Mutex<Database>>,
@@ -20,6 +25,16 @@ pub async fn unsub(
     let feed_url;
 
     match &*args {
+        [] => {
+            return send_keyboard(&db, &cmd, target, target_id).await;
+        }
+        [channel] if is_channel_arg(channel) => {
+            let channel_id = check_channel_permission(&cmd, channel, target).await?;
+            if channel_id.is_none() {
+                return Ok(());
+            }
+            return send_keyboard(&db, &cmd, target, channel_id.unwrap()).await;
+        }
         [url] => feed_url = url,
         [channel, url] => {
             let channel_id = check_channel_permission(&cmd, channel, target).await?;
@@ -47,3 +62,25 @@ pub async fn unsub(
     update_response(&cmd.bot, target, parameters::Text::with_html(&msg)).await?;
     Ok(())
 }
+
+async fn send_keyboard(
+    db: &Mutex<Database>,
+    cmd: &Command,
+    target: &mut MsgTarget,
+    target_id: tbot::types::chat::Id,
+) -> Result<(), tbot::errors::MethodCall> {
+    let mut feeds = db
+        .lock()
+        .await
+        .subscribed_feeds(target_id.0)
+        .unwrap_or_default();
+    sort_feeds(&mut feeds);
+    let (msg, keyboard) = render_page(&feeds, target_id.0, 0);
+    update_response_with_keyboard(
+        &cmd.bot,
+        target,
+        parameters::Text::with_plain(&msg),
+        keyboard,
+    )
+    .await
+}
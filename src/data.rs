@@ -1,15 +1,26 @@
+use std::cmp;
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 
-use atomicwrites::{AtomicFile, OverwriteBehavior};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use thiserror::Error;
 
 use crate::feed;
+use crate::filter::Filter;
+use crate::i18n::{self, Lang};
+use crate::template::Template;
+use crate::timezone::Timezone;
+
+use self::json::JsonStorage;
+use self::sqlite::SqliteStorage;
+
+mod json;
+mod sqlite;
 
 #[derive(Error, Debug)]
 pub enum DataError {
@@ -17,17 +28,52 @@ pub enum DataError {
     Io(#[from] std::io::Error),
     #[error("json error")]
     Json(#[from] serde_json::Error),
+    #[error("sqlite error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(
+        "database version {0} is newer than {}, please upgrade rssbot",
+        DATABASE_VERSION
+    )]
+    NewerVersion(u32),
+}
+
+/// Version of the on-disk format,
+/// add a migration to `json::MIGRATIONS` and `sqlite::MIGRATIONS` when increasing it
+///
+/// 0: item hashes were generated by `DefaultHasher`, which is not stable across Rust releases
+/// 1: item hashes are generated by `gen_hash`
+/// 2: subscriptions can override the link preview setting of the chat
+/// 3: messages of items are recorded to be edited
+/// 4: timezones can be zone names
+const DATABASE_VERSION: u32 = 4;
+
+/// A newer database may be misread, and then overwritten with the misread data
+fn check_version(version: u32) -> Result<(), DataError> {
+    if version > DATABASE_VERSION {
+        return Err(DataError::NewerVersion(version));
+    }
+    Ok(())
 }
 
-fn gen_hash<T: Hash>(t: &T) -> u64 {
-    let mut hasher = std::collections::hash_map::DefaultHasher::default();
-    t.hash(&mut hasher);
-    hasher.finish()
+/// 64-bit FNV-1a, the values are persisted so it must never change
+///
+/// http://www.isthe.com/chongo/tech/comp/fnv/index.html#FNV-1a
+fn gen_hash(s: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    s.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
 }
 
-type FeedId = u64;
+pub type FeedId = u64;
 type SubscriberId = i64;
 
+/// Stable across restarts, can be used to refer to a feed outside of the database
+pub fn feed_id(rss_link: &str) -> FeedId {
+    gen_hash(rss_link)
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Feed {
     pub link: String,
@@ -36,6 +82,239 @@ pub struct Feed {
     pub subscribers: HashSet<SubscriberId, Size64>,
     pub ttl: Option<u32>,
     hash_list: Vec<u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub filters: HashMap<SubscriberId, Filter, Size64>,
+    /// Subscribers not receiving updates, `hash_list` is still updated for them
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub paused: HashSet<SubscriberId, Size64>,
+    /// Overrides the template of the chat
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<SubscriberId, Template, Size64>,
+    /// Subscribers receiving enclosures as Telegram media
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub media: HashSet<SubscriberId, Size64>,
+    /// Overrides the link preview setting of the chat
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub link_previews: HashMap<SubscriberId, bool, Size64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// Consecutive fetch failures, fetching is backed off exponentially by it
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub error_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Don't fetch before this time, requested by the server with `Retry-After`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<SystemTime>,
+    /// `hash_list` is outdated, rebuild it silently on the next update
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    rehash: bool,
+}
+
+impl Feed {
+    /// Subscribers that haven't paused the subscription
+    pub fn active_subscribers(&self) -> impl Iterator<Item = SubscriberId> + '_ {
+        self.subscribers
+            .iter()
+            .copied()
+            .filter(move |subscriber| !self.paused.contains(subscriber))
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Per-chat preferences, chats with default settings are not stored
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chat {
+    #[serde(default)]
+    pub display_mode: DisplayMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    /// Layout of items, instead of the display mode's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<Template>,
+    /// Locale code, set by `/lang` or taken from the first user's Telegram settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// For dates in updates and schedules without a timezone, stored as `utc_offset`
+    /// before zone names were supported
+    #[serde(default, alias = "utc_offset", skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Timezone>,
+    /// Send updates without notification
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub silent: bool,
+    /// Let Telegram show a preview of links in updates
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub link_preview: bool,
+    /// For subscriptions without their own filter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    /// Edit sent messages when their items change, only items sent one per message
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edit_messages: bool,
+}
+
+impl Chat {
+    /// The language of messages to this chat
+    pub fn lang(&self) -> Lang {
+        self.lang_or(None)
+    }
+
+    /// The chosen language, or else the Telegram `language_code` of the user
+    pub fn lang_or(&self, language_code: Option<&str>) -> Lang {
+        self.language
+            .as_deref()
+            .or(language_code)
+            .and_then(Lang::find)
+            .unwrap_or_else(i18n::default_lang)
+    }
+}
+
+/// Messages are sent silently, or held if `hold` is set, between `start` and `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// Minutes after midnight
+    pub start: u32,
+    pub end: u32,
+    /// A copy of the chat's timezone if none was given, stored as `utc_offset` before
+    /// zone names were supported
+    #[serde(alias = "utc_offset")]
+    pub timezone: Timezone,
+    #[serde(default)]
+    pub hold: bool,
+}
+
+/// Collect new items and send them together at a time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub minute_of_day: u32,
+    /// A copy of the chat's timezone if none was given, stored as `utc_offset` before
+    /// zone names were supported
+    #[serde(alias = "utc_offset")]
+    pub timezone: Timezone,
+    /// Days from Monday, send weekly on this day if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weekday: Option<u32>,
+    /// The last time the digest was due
+    pub last_sent: SystemTime,
+}
+
+/// An item waiting for the digest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestItem {
+    pub feed_link: String,
+    pub feed_title: String,
+    pub title: Option<String>,
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayMode {
+    /// A list of titles in one message
+    #[default]
+    Compact,
+    /// One message per item, with summary, author and date
+    Detailed,
+}
+
+/// An item sent to chats which edit messages, to find out when it changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentItem {
+    /// From `item_key`
+    pub key: u64,
+    /// From `gen_item_fingerprint`, of the content when it was last sent or edited
+    pub fingerprint: u64,
+    /// Recorded once sent
+    #[serde(default)]
+    pub messages: Vec<SentMessage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub chat: SubscriberId,
+    pub message_id: u32,
+}
+
+/// Consecutive failures of delivering messages to a chat
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryFailure {
+    pub count: u32,
+    pub since: SystemTime,
+    pub last_error: String,
+}
+
+/// Where the database is persisted, the whole database is also kept in memory
+trait Storage: Send + fmt::Debug {
+    /// Read everything, `None` if nothing was stored yet
+    fn load(&mut self) -> Result<Option<Contents>, DataError>;
+    /// Write the changes, `tables` is the database after them
+    fn save(&mut self, tables: &Tables, changes: &[Change]) -> Result<(), DataError>;
+}
+
+/// A changed part of the database, records not in the database anymore are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// Everything, f.e. after a migration
+    All,
+    /// A feed with its hash list, a removed feed takes its subscriptions along
+    Feed(FeedId),
+    /// Only the down time and errors of a feed, failing feeds back off,
+    /// so they change rarely
+    FeedState(FeedId),
+    /// The settings of a subscriber in a feed, removed when not subscribed anymore
+    Subscription(FeedId, SubscriberId),
+    Chat(SubscriberId),
+    DeliveryFailure(SubscriberId),
+    DigestItems(SubscriberId),
+    SentItems(FeedId),
+}
+
+/// Everything loaded from a storage, migrated to `DATABASE_VERSION`
+struct Contents {
+    /// The version it was stored with
+    version: u32,
+    feeds: Vec<Feed>,
+    chats: HashMap<SubscriberId, Chat, Size64>,
+    delivery_failures: HashMap<SubscriberId, DeliveryFailure, Size64>,
+    digest_items: HashMap<SubscriberId, Vec<DigestItem>, Size64>,
+    sent_items: HashMap<FeedId, Vec<SentItem>, Size64>,
+}
+
+/// The database, for storages to look up the changes
+struct Tables<'a> {
+    feeds: &'a HashMap<FeedId, Feed, Size64>,
+    chats: &'a HashMap<SubscriberId, Chat, Size64>,
+    delivery_failures: &'a HashMap<SubscriberId, DeliveryFailure, Size64>,
+    digest_items: &'a HashMap<SubscriberId, Vec<DigestItem>, Size64>,
+    sent_items: &'a HashMap<FeedId, Vec<SentItem>, Size64>,
+}
+
+/// Selected by `--storage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// One JSON file, rewritten on every change
+    Json,
+    /// Only the changed records are written
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StorageKind::Json),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err("must be json or sqlite".into()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -46,51 +325,102 @@ pub struct Hub {
 
 #[derive(Debug)]
 pub struct Database {
-    path: PathBuf,
+    storage: Box<dyn Storage>,
     feeds: HashMap<FeedId, Feed, Size64>,
     subscribers: HashMap<SubscriberId, HashSet<FeedId, Size64>, Size64>,
+    chats: HashMap<SubscriberId, Chat, Size64>,
+    delivery_failures: HashMap<SubscriberId, DeliveryFailure, Size64>,
+    digest_items: HashMap<SubscriberId, Vec<DigestItem>, Size64>,
+    /// Only for feeds with subscribers editing messages
+    sent_items: HashMap<FeedId, Vec<SentItem>, Size64>,
+    /// Changes held back by `defer_saves`
+    deferred: Option<Vec<Change>>,
 }
 
 impl Database {
     pub fn create(path: PathBuf) -> Result<Database, DataError> {
-        let result = Database {
-            path,
-            feeds: HashMap::with_hasher(Size64::default()),
-            subscribers: HashMap::with_hasher(Size64::default()),
-        };
+        Database::with_contents(Box::new(JsonStorage::new(path)), None, false)
+    }
 
-        result.save()?;
+    pub fn open(path: PathBuf) -> Result<Database, DataError> {
+        let mut storage = JsonStorage::new(path);
+        let contents = storage.load()?;
+        Database::with_contents(Box::new(storage), contents, false)
+    }
 
-        Ok(result)
+    /// Use an SQLite database next to `path`, with the extension replaced by `sqlite`,
+    /// the JSON database is imported when the SQLite database is created
+    pub fn open_sqlite(path: PathBuf) -> Result<Database, DataError> {
+        let sqlite_path = path.with_extension("sqlite");
+        let json_path = path.with_extension("json");
+        let mut storage = SqliteStorage::open(&sqlite_path)?;
+        let (contents, imported) = match storage.load()? {
+            Some(contents) => (Some(contents), false),
+            None => (JsonStorage::new(json_path.clone()).load()?, true),
+        };
+        if imported && contents.is_some() {
+            eprintln!(
+                "Importing {} into {}",
+                json_path.display(),
+                sqlite_path.display()
+            );
+        }
+        Database::with_contents(Box::new(storage), contents, imported)
     }
 
-    pub fn open(path: PathBuf) -> Result<Database, DataError> {
-        if path.exists() {
-            let f = File::open(&path)?;
-            let feeds_list: Vec<Feed> = serde_json::from_reader(&f)?;
-
-            let mut feeds = HashMap::with_capacity_and_hasher(feeds_list.len(), Size64::default());
-            let mut subscribers = HashMap::with_hasher(Size64::default());
-
-            for feed in feeds_list {
-                let feed_id = gen_hash(&feed.link);
-                for subscriber in &feed.subscribers {
-                    let subscribed_feeds = subscribers
-                        .entry(subscriber.to_owned())
-                        .or_insert_with(HashSet::default);
-                    subscribed_feeds.insert(feed_id);
-                }
-                feeds.insert(feed_id, feed);
+    /// Everything is written to the storage if it's new, outdated, or `imported` from another
+    fn with_contents(
+        storage: Box<dyn Storage>,
+        contents: Option<Contents>,
+        imported: bool,
+    ) -> Result<Database, DataError> {
+        let mut db = Database {
+            storage,
+            feeds: HashMap::with_hasher(Size64::default()),
+            subscribers: HashMap::with_hasher(Size64::default()),
+            chats: HashMap::with_hasher(Size64::default()),
+            delivery_failures: HashMap::with_hasher(Size64::default()),
+            digest_items: HashMap::with_hasher(Size64::default()),
+            sent_items: HashMap::with_hasher(Size64::default()),
+            deferred: None,
+        };
+        let contents = match contents {
+            Some(contents) => contents,
+            None => {
+                db.save(&[Change::All])?;
+                return Ok(db);
             }
+        };
+        let Contents {
+            version,
+            feeds: feeds_list,
+            chats,
+            delivery_failures,
+            digest_items,
+            sent_items,
+        } = contents;
 
-            Ok(Database {
-                path,
-                feeds,
-                subscribers,
-            })
-        } else {
-            Database::create(path)
+        db.feeds.reserve(feeds_list.len());
+        for feed in feeds_list {
+            let feed_id = gen_hash(&feed.link);
+            for subscriber in &feed.subscribers {
+                let subscribed_feeds = db
+                    .subscribers
+                    .entry(subscriber.to_owned())
+                    .or_insert_with(HashSet::default);
+                subscribed_feeds.insert(feed_id);
+            }
+            db.feeds.insert(feed_id, feed);
+        }
+        db.chats = chats;
+        db.delivery_failures = delivery_failures;
+        db.digest_items = digest_items;
+        db.sent_items = sent_items;
+
+        if version < DATABASE_VERSION || imported {
+            db.save(&[Change::All])?;
         }
+        Ok(db)
     }
 
     pub fn all_feeds(&self) -> Vec<Feed> {
@@ -111,38 +441,203 @@ impl Database {
         })
     }
 
+    pub fn chat(&self, subscriber: SubscriberId) -> Chat {
+        self.chats.get(&subscriber).cloned().unwrap_or_default()
+    }
+
+    pub fn set_chat(&mut self, subscriber: SubscriberId, chat: Chat) {
+        if chat == Chat::default() {
+            self.chats.remove(&subscriber);
+        } else {
+            self.chats.insert(subscriber, chat);
+        }
+        self.save(&[Change::Chat(subscriber)]).unwrap_or_default();
+    }
+
+    /// Keep the items until the digest of the subscriber is sent
+    pub fn add_digest_items(
+        &mut self,
+        subscriber: SubscriberId,
+        feed: &Feed,
+        items: &[feed::Item],
+    ) {
+        let pending = self.digest_items.entry(subscriber).or_default();
+        pending.extend(items.iter().map(|item| DigestItem {
+            feed_link: feed.link.clone(),
+            feed_title: feed.title.clone(),
+            title: item.title.clone(),
+            link: item.link.clone(),
+        }));
+        self.save(&[Change::DigestItems(subscriber)])
+            .unwrap_or_default();
+    }
+
+    /// Subscribers with a digest schedule or pending items
+    pub fn digest_subscribers(&self) -> Vec<SubscriberId> {
+        let mut result: Vec<SubscriberId> = self
+            .chats
+            .iter()
+            .filter(|(_, chat)| chat.digest.is_some())
+            .map(|(subscriber, _)| *subscriber)
+            .chain(self.digest_items.keys().copied())
+            .collect();
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    /// Remove the pending items and record the time the digest is sent
+    pub fn take_digest_items(
+        &mut self,
+        subscriber: SubscriberId,
+        now: SystemTime,
+    ) -> Vec<DigestItem> {
+        if let Some(digest) = self
+            .chats
+            .get_mut(&subscriber)
+            .and_then(|chat| chat.digest.as_mut())
+        {
+            digest.last_sent = now;
+        }
+        let items = self.digest_items.remove(&subscriber).unwrap_or_default();
+        self.save(&[Change::Chat(subscriber), Change::DigestItems(subscriber)])
+            .unwrap_or_default();
+        items
+    }
+
+    /// Return the number of consecutive failures
+    pub fn record_delivery_failure(&mut self, subscriber: SubscriberId, error: String) -> u32 {
+        let failure = self
+            .delivery_failures
+            .entry(subscriber)
+            .or_insert_with(|| DeliveryFailure {
+                count: 0,
+                since: SystemTime::now(),
+                last_error: String::new(),
+            });
+        failure.count = failure.count.saturating_add(1);
+        failure.last_error = error;
+        let count = failure.count;
+        self.save(&[Change::DeliveryFailure(subscriber)])
+            .unwrap_or_default();
+        count
+    }
+
+    pub fn reset_delivery_failure(&mut self, subscriber: SubscriberId) -> bool {
+        let removed = self.delivery_failures.remove(&subscriber).is_some();
+        if removed {
+            self.save(&[Change::DeliveryFailure(subscriber)])
+                .unwrap_or_default();
+        }
+        removed
+    }
+
+    pub fn delivery_failure(&self, subscriber: SubscriberId) -> Option<&DeliveryFailure> {
+        self.delivery_failures.get(&subscriber)
+    }
+
     /// Return `None` if feed not found
     pub fn get_or_update_down_time(&mut self, rss_link: &str) -> Option<Duration> {
-        let feed_id = gen_hash(&rss_link);
+        let feed_id = gen_hash(rss_link);
         let feed = self.feeds.get_mut(&feed_id)?;
         let now = SystemTime::now();
         if let Some(t) = feed.down_time {
             Some(now.duration_since(t).unwrap_or_default())
         } else {
             feed.down_time = Some(now);
+            self.save(&[Change::FeedState(feed_id)]).unwrap_or_default();
             Some(Duration::default())
         }
     }
 
     pub fn reset_down_time(&mut self, rss_link: &str) -> bool {
-        let feed_id = gen_hash(&rss_link);
-        self.feeds
+        let feed_id = gen_hash(rss_link);
+        let found = self
+            .feeds
             .get_mut(&feed_id)
             .map(|feed| {
                 feed.down_time = None;
             })
-            .is_some()
+            .is_some();
+        if found {
+            self.save(&[Change::FeedState(feed_id)]).unwrap_or_default();
+        }
+        found
+    }
+
+    pub fn record_fetch_error(&mut self, rss_link: &str, error: String) -> bool {
+        let feed_id = gen_hash(rss_link);
+        let found = self
+            .feeds
+            .get_mut(&feed_id)
+            .map(|feed| {
+                feed.error_count = feed.error_count.saturating_add(1);
+                feed.last_error = Some(error);
+            })
+            .is_some();
+        if found {
+            self.save(&[Change::FeedState(feed_id)]).unwrap_or_default();
+        }
+        found
+    }
+
+    /// Don't fetch these feeds until the given time
+    pub fn postpone<'a, I: IntoIterator<Item = &'a str>>(
+        &mut self,
+        rss_links: I,
+        until: SystemTime,
+    ) {
+        let mut changes = Vec::new();
+        for rss_link in rss_links {
+            let feed_id = gen_hash(rss_link);
+            if let Some(feed) = self.feeds.get_mut(&feed_id) {
+                feed.retry_after = Some(cmp::max(feed.retry_after.unwrap_or(until), until));
+                changes.push(Change::FeedState(feed_id));
+            }
+        }
+        self.save(&changes).unwrap_or_default();
+    }
+
+    pub fn is_postponed(&self, rss_link: &str) -> bool {
+        self.feeds
+            .get(&gen_hash(rss_link))
+            .and_then(|feed| feed.retry_after)
+            .map(|t| t > SystemTime::now())
+            .unwrap_or(false)
+    }
+
+    /// Reset the down time and the failure count after a successful fetch
+    pub fn reset_fetch_errors(&mut self, rss_link: &str) -> bool {
+        let feed_id = gen_hash(rss_link);
+        let changed = match self.feeds.get_mut(&feed_id) {
+            Some(feed) => {
+                let changed = feed.down_time.is_some()
+                    || feed.error_count != 0
+                    || feed.last_error.is_some()
+                    || feed.retry_after.is_some();
+                feed.down_time = None;
+                feed.error_count = 0;
+                feed.last_error = None;
+                feed.retry_after = None;
+                changed
+            }
+            None => return false,
+        };
+        if changed {
+            self.save(&[Change::FeedState(feed_id)]).unwrap_or_default();
+        }
+        true
     }
 
     pub fn is_subscribed(&self, subscriber: SubscriberId, rss_link: &str) -> bool {
         self.subscribers
             .get(&subscriber)
-            .map(|feeds| feeds.contains(&gen_hash(&rss_link)))
+            .map(|feeds| feeds.contains(&gen_hash(rss_link)))
             .unwrap_or(false)
     }
 
     pub fn subscribe(&mut self, subscriber: SubscriberId, rss_link: &str, rss: &feed::Rss) -> bool {
-        let feed_id = gen_hash(&rss_link);
+        let feed_id = gen_hash(rss_link);
         {
             let subscribed_feeds = self
                 .subscribers
@@ -152,6 +647,11 @@ impl Database {
                 return false;
             }
         }
+        let mut changes = Vec::new();
+        if !self.feeds.contains_key(&feed_id) {
+            changes.push(Change::Feed(feed_id));
+        }
+        changes.push(Change::Subscription(feed_id, subscriber));
         {
             let feed = self.feeds.entry(feed_id).or_insert_with(|| Feed {
                 link: rss_link.to_owned(),
@@ -160,15 +660,26 @@ impl Database {
                 ttl: rss.ttl,
                 hash_list: rss.items.iter().map(gen_item_hash).collect(),
                 subscribers: HashSet::default(),
+                filters: HashMap::default(),
+                paused: HashSet::default(),
+                media: HashSet::default(),
+                templates: HashMap::default(),
+                link_previews: HashMap::default(),
+                etag: None,
+                last_modified: None,
+                error_count: 0,
+                last_error: None,
+                retry_after: None,
+                rehash: false,
             });
             feed.subscribers.insert(subscriber);
         }
-        self.save().unwrap_or_default();
+        self.save(&changes).unwrap_or_default();
         true
     }
 
     pub fn unsubscribe(&mut self, subscriber: SubscriberId, rss_link: &str) -> Option<Feed> {
-        let feed_id = gen_hash(&rss_link);
+        let feed_id = gen_hash(rss_link);
 
         let clear_subscriber;
         if let Some(subscribed_feeds) = self.subscribers.get_mut(&subscriber) {
@@ -188,6 +699,11 @@ impl Database {
         let clear_feed;
         if let Some(feed) = self.feeds.get_mut(&feed_id) {
             if feed.subscribers.remove(&subscriber) {
+                feed.filters.remove(&subscriber);
+                feed.paused.remove(&subscriber);
+                feed.media.remove(&subscriber);
+                feed.templates.remove(&subscriber);
+                feed.link_previews.remove(&subscriber);
                 clear_feed = feed.subscribers.is_empty();
                 result = feed.clone();
             } else {
@@ -198,12 +714,32 @@ impl Database {
         };
         if clear_feed {
             self.feeds.remove(&feed_id);
+            self.sent_items.remove(&feed_id);
+        } else if let Some(items) = self.sent_items.get_mut(&feed_id) {
+            for sent in items {
+                sent.messages.retain(|message| message.chat != subscriber);
+            }
         }
-        self.save().unwrap_or_default();
+        let removed = if clear_feed {
+            Change::Feed(feed_id)
+        } else {
+            Change::Subscription(feed_id, subscriber)
+        };
+        self.save(&[removed, Change::SentItems(feed_id)])
+            .unwrap_or_default();
         Some(result)
     }
 
     pub fn delete_subscriber(&mut self, subscriber: SubscriberId) -> bool {
+        self.chats.remove(&subscriber);
+        self.delivery_failures.remove(&subscriber);
+        self.digest_items.remove(&subscriber);
+        self.save(&[
+            Change::Chat(subscriber),
+            Change::DeliveryFailure(subscriber),
+            Change::DigestItems(subscriber),
+        ])
+        .unwrap_or_default();
         self.subscribed_feeds(subscriber)
             .map(|feeds| {
                 for feed in feeds {
@@ -214,42 +750,285 @@ impl Database {
     }
 
     pub fn update_subscriber(&mut self, from: SubscriberId, to: SubscriberId) -> bool {
-        self.subscribers
+        if let Some(chat) = self.chats.remove(&from) {
+            self.chats.insert(to, chat);
+        }
+        if let Some(failure) = self.delivery_failures.remove(&from) {
+            self.delivery_failures.insert(to, failure);
+        }
+        if let Some(items) = self.digest_items.remove(&from) {
+            self.digest_items.insert(to, items);
+        }
+        let mut changes = Vec::new();
+        for subscriber in [from, to] {
+            changes.push(Change::Chat(subscriber));
+            changes.push(Change::DeliveryFailure(subscriber));
+            changes.push(Change::DigestItems(subscriber));
+        }
+        let updated = self
+            .subscribers
             .remove(&from)
             .map(|feeds| {
                 for feed_id in &feeds {
+                    changes.push(Change::Subscription(*feed_id, from));
+                    changes.push(Change::Subscription(*feed_id, to));
                     let feed = self.feeds.get_mut(&feed_id).unwrap();
                     feed.subscribers.remove(&from);
                     feed.subscribers.insert(to);
+                    if let Some(filter) = feed.filters.remove(&from) {
+                        feed.filters.insert(to, filter);
+                    }
+                    if feed.paused.remove(&from) {
+                        feed.paused.insert(to);
+                    }
+                    if feed.media.remove(&from) {
+                        feed.media.insert(to);
+                    }
+                    if let Some(template) = feed.templates.remove(&from) {
+                        feed.templates.insert(to, template);
+                    }
+                    if let Some(link_preview) = feed.link_previews.remove(&from) {
+                        feed.link_previews.insert(to, link_preview);
+                    }
+                    if let Some(items) = self.sent_items.get_mut(feed_id) {
+                        changes.push(Change::SentItems(*feed_id));
+                        let messages = items.iter_mut().flat_map(|sent| &mut sent.messages);
+                        for message in messages.filter(|message| message.chat == from) {
+                            message.chat = to;
+                        }
+                    }
                 }
                 self.subscribers.insert(to, feeds);
             })
-            .is_some()
+            .is_some();
+        self.save(&changes).unwrap_or_default();
+        updated
+    }
+
+    pub fn filter(&self, subscriber: SubscriberId, rss_link: &str) -> Option<&Filter> {
+        self.feeds
+            .get(&gen_hash(rss_link))
+            .and_then(|feed| feed.filters.get(&subscriber))
+    }
+
+    /// Set or clear (with an empty filter) the filter of a subscription,
+    /// return `false` if not subscribed
+    pub fn set_filter(&mut self, subscriber: SubscriberId, rss_link: &str, filter: Filter) -> bool {
+        if !self.is_subscribed(subscriber, rss_link) {
+            return false;
+        }
+        let feed = self.feeds.get_mut(&gen_hash(rss_link)).unwrap();
+        if filter.is_empty() {
+            feed.filters.remove(&subscriber);
+        } else {
+            feed.filters.insert(subscriber, filter);
+        }
+        self.save(&[Change::Subscription(gen_hash(rss_link), subscriber)])
+            .unwrap_or_default();
+        true
+    }
+
+    /// The template of a subscription, not including the chat's
+    pub fn template(&self, subscriber: SubscriberId, rss_link: &str) -> Option<&Template> {
+        self.feeds
+            .get(&gen_hash(rss_link))
+            .and_then(|feed| feed.templates.get(&subscriber))
+    }
+
+    /// Set or clear the template of a subscription, return `false` if not subscribed
+    pub fn set_template(
+        &mut self,
+        subscriber: SubscriberId,
+        rss_link: &str,
+        template: Option<Template>,
+    ) -> bool {
+        if !self.is_subscribed(subscriber, rss_link) {
+            return false;
+        }
+        let feed = self.feeds.get_mut(&gen_hash(rss_link)).unwrap();
+        match template {
+            Some(template) => feed.templates.insert(subscriber, template),
+            None => feed.templates.remove(&subscriber),
+        };
+        self.save(&[Change::Subscription(gen_hash(rss_link), subscriber)])
+            .unwrap_or_default();
+        true
+    }
+
+    pub fn media_enabled(&self, subscriber: SubscriberId, rss_link: &str) -> bool {
+        self.feeds
+            .get(&gen_hash(rss_link))
+            .map(|feed| feed.media.contains(&subscriber))
+            .unwrap_or(false)
+    }
+
+    /// Send enclosures of a subscription as media or not, return `false` if not subscribed
+    pub fn set_media(&mut self, subscriber: SubscriberId, rss_link: &str, enabled: bool) -> bool {
+        if !self.is_subscribed(subscriber, rss_link) {
+            return false;
+        }
+        let feed = self.feeds.get_mut(&gen_hash(rss_link)).unwrap();
+        if enabled {
+            feed.media.insert(subscriber);
+        } else {
+            feed.media.remove(&subscriber);
+        }
+        self.save(&[Change::Subscription(gen_hash(rss_link), subscriber)])
+            .unwrap_or_default();
+        true
+    }
+
+    /// The link preview setting of a subscription, not including the chat's
+    pub fn link_preview(&self, subscriber: SubscriberId, rss_link: &str) -> Option<bool> {
+        self.feeds
+            .get(&gen_hash(rss_link))
+            .and_then(|feed| feed.link_previews.get(&subscriber))
+            .copied()
+    }
+
+    /// Override the link preview setting of the chat for a subscription, or follow it again
+    /// with `None`, return `false` if not subscribed
+    pub fn set_link_preview(
+        &mut self,
+        subscriber: SubscriberId,
+        rss_link: &str,
+        link_preview: Option<bool>,
+    ) -> bool {
+        if !self.is_subscribed(subscriber, rss_link) {
+            return false;
+        }
+        let feed = self.feeds.get_mut(&gen_hash(rss_link)).unwrap();
+        match link_preview {
+            Some(link_preview) => feed.link_previews.insert(subscriber, link_preview),
+            None => feed.link_previews.remove(&subscriber),
+        };
+        self.save(&[Change::Subscription(gen_hash(rss_link), subscriber)])
+            .unwrap_or_default();
+        true
+    }
+
+    /// Pause or resume a subscription, or all subscriptions of the subscriber if `rss_link`
+    /// is `None`, return the number of changed subscriptions
+    pub fn set_paused(
+        &mut self,
+        subscriber: SubscriberId,
+        rss_link: Option<&str>,
+        paused: bool,
+    ) -> usize {
+        let feed_ids: Vec<u64> = match rss_link {
+            Some(rss_link) if self.is_subscribed(subscriber, rss_link) => vec![gen_hash(rss_link)],
+            Some(_) => return 0,
+            None => match self.subscribers.get(&subscriber) {
+                Some(feeds) => feeds.iter().copied().collect(),
+                None => return 0,
+            },
+        };
+        let mut changes = Vec::new();
+        for feed_id in feed_ids {
+            let feed = self.feeds.get_mut(&feed_id).unwrap();
+            let updated = if paused {
+                feed.paused.insert(subscriber)
+            } else {
+                feed.paused.remove(&subscriber)
+            };
+            if updated {
+                changes.push(Change::Subscription(feed_id, subscriber));
+            }
+        }
+        if !changes.is_empty() {
+            self.save(&changes).unwrap_or_default();
+        }
+        changes.len()
     }
 
     /// Update the feed in database, return updates
-    pub fn update(&mut self, rss_link: &str, new_feed: feed::Rss) -> Vec<FeedUpdate> {
-        let feed_id = gen_hash(&rss_link);
+    ///
+    /// `etag` and `last_modified` are the validators of the response,
+    /// they will be sent with the next request
+    pub fn update(
+        &mut self,
+        rss_link: &str,
+        new_feed: feed::Rss,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    ) -> Vec<FeedUpdate> {
+        let feed_id = gen_hash(rss_link);
         if !self.feeds.contains_key(&feed_id) {
             return Vec::new();
         }
 
-        self.reset_down_time(rss_link);
+        let recovered = self.feeds[&feed_id].error_count != 0;
+        self.reset_fetch_errors(rss_link);
         let feed = self.feeds.get_mut(&feed_id).unwrap();
+        let chats = &self.chats;
+        let tracked = feed
+            .subscribers
+            .iter()
+            .any(|subscriber| chats.get(subscriber).is_some_and(|chat| chat.edit_messages));
+        let old_sent_items = self.sent_items.get(&feed_id).cloned().unwrap_or_default();
+        let mut unseen_sent_items = old_sent_items.clone();
+        // Rebuilt from the fetched items, so the items gone from the feed are dropped
+        let mut sent_items = Vec::new();
 
         let mut updates = Vec::new();
+        let rehashed = feed.rehash;
         let mut new_items = Vec::new();
+        let mut edited_items = Vec::new();
         let mut new_hash_list = Vec::new();
         let items_len = new_feed.items.len();
         for item in new_feed.items {
             let hash = gen_item_hash(&item);
-            if !feed.hash_list.contains(&hash) {
+            let is_new = !feed.hash_list.contains(&hash);
+            if let Some(key) = item_key(&item).filter(|_| tracked) {
+                let fingerprint = gen_item_fingerprint(&item);
+                match unseen_sent_items.iter().position(|sent| sent.key == key) {
+                    Some(i) => {
+                        // Without an id, the hash of an item changes with its title
+                        if is_new {
+                            new_hash_list.push(hash);
+                        }
+                        let mut sent = unseen_sent_items.swap_remove(i);
+                        let changed = sent.fingerprint != fingerprint;
+                        sent.fingerprint = fingerprint;
+                        // Paused chats keep their messages as they were sent
+                        let messages: Vec<SentMessage> = sent
+                            .messages
+                            .iter()
+                            .filter(|message| {
+                                feed.subscribers.contains(&message.chat)
+                                    && !feed.paused.contains(&message.chat)
+                            })
+                            .copied()
+                            .collect();
+                        sent_items.push(sent);
+                        if changed && !messages.is_empty() {
+                            edited_items.push((item, messages));
+                        }
+                        continue;
+                    }
+                    None if is_new => sent_items.push(SentItem {
+                        key,
+                        fingerprint,
+                        messages: Vec::new(),
+                    }),
+                    None => (),
+                }
+            }
+            if is_new {
                 new_hash_list.push(hash);
                 new_items.push(item);
             }
         }
-        if !new_items.is_empty() {
-            updates.push(FeedUpdate::Items(new_items));
+        if !edited_items.is_empty() {
+            updates.push(FeedUpdate::Edited(edited_items));
+        }
+        if feed.rehash {
+            feed.rehash = false;
+            feed.hash_list = new_hash_list;
+        } else if !new_hash_list.is_empty() {
+            if !new_items.is_empty() {
+                updates.push(FeedUpdate::Items(new_items));
+            }
 
             let max_size = items_len * 2;
             let mut append: Vec<u64> = feed
@@ -266,34 +1045,110 @@ impl Database {
             feed.title = new_feed.title;
         }
         feed.ttl = new_feed.ttl;
-        if !updates.is_empty() {
-            self.save().unwrap_or_default();
+        let validators_changed = feed.etag != etag || feed.last_modified != last_modified;
+        feed.etag = etag;
+        feed.last_modified = last_modified;
+        let mut changes = Vec::new();
+        if !updates.is_empty() || validators_changed || rehashed || recovered {
+            changes.push(Change::Feed(feed_id));
+        }
+        if sent_items != old_sent_items {
+            if sent_items.is_empty() {
+                self.sent_items.remove(&feed_id);
+            } else {
+                self.sent_items.insert(feed_id, sent_items);
+            }
+            changes.push(Change::SentItems(feed_id));
+        }
+        if !changes.is_empty() {
+            self.save(&changes).unwrap_or_default();
         }
         updates
     }
 
-    pub fn save(&self) -> Result<(), DataError> {
-        let feeds_list: Vec<&Feed> = self.feeds.iter().map(|(_id, feed)| feed).collect();
-        let file = AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite);
-        file.write(|file| serde_json::to_writer(file, &feeds_list))
-            .map_err(|e| match e {
-                atomicwrites::Error::Internal(e) => DataError::Io(e),
-                atomicwrites::Error::User(e) => {
-                    assert!(!e.is_io(), "unreachable code");
-                    DataError::Io(e.into())
-                }
-            })?;
-        Ok(())
+    /// Remember the message of a sent item, to edit it when the item changes
+    pub fn record_sent_message(
+        &mut self,
+        feed: FeedId,
+        item: u64,
+        subscriber: SubscriberId,
+        message_id: u32,
+    ) {
+        let sent = self
+            .sent_items
+            .get_mut(&feed)
+            .and_then(|items| items.iter_mut().find(|sent| sent.key == item));
+        if let Some(sent) = sent {
+            sent.messages.retain(|message| message.chat != subscriber);
+            sent.messages.push(SentMessage {
+                chat: subscriber,
+                message_id,
+            });
+            self.save(&[Change::SentItems(feed)]).unwrap_or_default();
+        }
+    }
+
+    /// Hold back the saves until `save_deferred`, f.e. new items are saved as seen
+    /// only after their messages are written to the outbox
+    pub fn defer_saves(&mut self) {
+        self.deferred.get_or_insert_with(Vec::new);
+    }
+
+    pub fn save_deferred(&mut self) {
+        if let Some(changes) = self.deferred.take() {
+            if !changes.is_empty() {
+                self.save(&changes).unwrap_or_default();
+            }
+        }
+    }
+
+    fn save(&mut self, changes: &[Change]) -> Result<(), DataError> {
+        if let Some(deferred) = &mut self.deferred {
+            deferred.extend_from_slice(changes);
+            return Ok(());
+        }
+        let tables = Tables {
+            feeds: &self.feeds,
+            chats: &self.chats,
+            delivery_failures: &self.delivery_failures,
+            digest_items: &self.digest_items,
+            sent_items: &self.sent_items,
+        };
+        self.storage.save(&tables, changes)
     }
 }
 
 pub enum FeedUpdate {
     Items(Vec<feed::Item>),
+    /// Sent items that changed, with their messages in chats that haven't paused the feed
+    Edited(Vec<(feed::Item, Vec<SentMessage>)>),
     Title(String),
 }
 
+/// Identifies an item across its changes, unlike `gen_item_hash`
+pub fn item_key(item: &feed::Item) -> Option<u64> {
+    item.id.as_deref().or(item.link.as_deref()).map(gen_hash)
+}
+
+/// Changes when anything shown of the item changes
+fn gen_item_fingerprint(item: &feed::Item) -> u64 {
+    let fields = [
+        &item.title,
+        &item.link,
+        &item.author,
+        &item.summary,
+        &item.content,
+    ];
+    let joined = fields
+        .iter()
+        .map(|field| field.as_deref().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\0");
+    gen_hash(&joined)
+}
+
 fn gen_item_hash(item: &feed::Item) -> u64 {
-    item.id.as_ref().map(|id| gen_hash(&id)).unwrap_or_else(|| {
+    item.id.as_deref().map(gen_hash).unwrap_or_else(|| {
         let title = item.title.as_ref().map(|s| s.as_str()).unwrap_or_default();
         let link = item.link.as_ref().map(|s| s.as_str()).unwrap_or_default();
         gen_hash(&format!("{}{}", title, link))
@@ -335,9 +1190,438 @@ impl Hasher for Size64Hasher {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use std::path::Path;
+
     use super::*;
 
+    /// `rssbot-<name>-<pid>.<extension>` in the temp dir, removed with the files next to it
+    /// (backups, the SQLite database and its journals) when dropped, also if the test fails
+    pub struct TempPath(PathBuf);
+
+    impl TempPath {
+        pub fn new(name: &str, extension: &str) -> TempPath {
+            let file_name = format!("rssbot-{}-{}.{}", name, std::process::id(), extension);
+            TempPath(std::env::temp_dir().join(file_name))
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let prefix = format!("{}.", self.0.file_stem().unwrap().to_string_lossy());
+            let entries = match std::fs::read_dir(self.0.parent().unwrap()) {
+                Ok(entries) => entries,
+                Err(_) => return,
+            };
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn open_legacy_format() {
+        let path = TempPath::new("legacy", "json");
+        std::fs::write(
+            &path,
+            r#"[{"link":"http://example.com/feed.xml","title":"title","down_time":null,"subscribers":[42],"ttl":null,"hash_list":[]}]"#,
+        )
+        .unwrap();
+        let mut db = Database::open(path.clone()).unwrap();
+        assert!(db.is_subscribed(42, "http://example.com/feed.xml"));
+        db.set_chat(
+            42,
+            Chat {
+                display_mode: DisplayMode::Detailed,
+                ..Default::default()
+            },
+        );
+        db.set_filter(42, "http://example.com/feed.xml", "+a".parse().unwrap());
+
+        let db = Database::open(path.clone()).unwrap();
+        assert!(db.is_subscribed(42, "http://example.com/feed.xml"));
+        assert_eq!(db.chat(42).display_mode, DisplayMode::Detailed);
+        assert_eq!(
+            db.filter(42, "http://example.com/feed.xml")
+                .map(|f| f.to_string()),
+            Some("+a".into())
+        );
+        let backup = json::backup_path(&path, 0);
+        assert!(std::fs::read_to_string(backup).unwrap().starts_with('['));
+    }
+
+    #[test]
+    fn newer_version() {
+        let path = TempPath::new("newer", "json");
+        let content = format!(r#"{{"version":{},"feeds":[]}}"#, DATABASE_VERSION + 1);
+        std::fs::write(&path, &content).unwrap();
+        assert!(matches!(
+            Database::open(path.clone()),
+            Err(DataError::NewerVersion(v)) if v == DATABASE_VERSION + 1
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn delivery_failures() {
+        let path = TempPath::new("failures", "json");
+        let mut db = Database::create(path.clone()).unwrap();
+        assert_eq!(db.record_delivery_failure(1, "a".into()), 1);
+        assert_eq!(db.record_delivery_failure(1, "b".into()), 2);
+
+        let mut db = Database::open(path.clone()).unwrap();
+        db.update_subscriber(1, 2);
+        assert_eq!(db.delivery_failure(1), None);
+        let failure = db.delivery_failure(2).unwrap();
+        assert_eq!((failure.count, &*failure.last_error), (2, "b"));
+        assert!(db.reset_delivery_failure(2));
+        assert!(!db.reset_delivery_failure(2));
+    }
+
+    #[test]
+    fn fetch_errors() {
+        let path = TempPath::new("errors", "json");
+        let link = "http://a.com/feed.xml";
+        let mut db = Database::create(path.clone()).unwrap();
+        db.subscribe(1, link, &feed::Rss::default());
+        db.record_fetch_error(link, "a".into());
+        db.record_fetch_error(link, "b".into());
+
+        // Kept across restarts, so the backoff goes on
+        let mut db = Database::open(path.clone()).unwrap();
+        let feed = db.all_feeds().pop().unwrap();
+        assert_eq!(
+            (feed.error_count, feed.last_error.as_deref()),
+            (2, Some("b"))
+        );
+        assert!(db.reset_fetch_errors(link));
+        let feed = Database::open(path.clone())
+            .unwrap()
+            .all_feeds()
+            .pop()
+            .unwrap();
+        assert_eq!(feed.error_count, 0);
+    }
+
+    #[test]
+    fn digest_items() {
+        let path = TempPath::new("digest", "json");
+        let mut db = Database::create(path.clone()).unwrap();
+        let rss = feed::Rss {
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.subscribe(1, "http://a.com/feed.xml", &rss);
+        let feed = db.subscribed_feeds(1).unwrap().pop().unwrap();
+        let item = feed::Item {
+            title: Some("item".into()),
+            ..Default::default()
+        };
+        db.add_digest_items(1, &feed, &[item.clone(), item]);
+        let digest = Digest {
+            minute_of_day: 9 * 60,
+            timezone: Timezone::UTC,
+            weekday: None,
+            last_sent: SystemTime::UNIX_EPOCH,
+        };
+        db.set_chat(
+            3,
+            Chat {
+                digest: Some(digest),
+                ..Default::default()
+            },
+        );
+        assert_eq!(db.digest_subscribers(), vec![1, 3]);
+
+        let mut db = Database::open(path.clone()).unwrap();
+        db.update_subscriber(1, 2);
+        assert_eq!(db.digest_subscribers(), vec![2, 3]);
+        let items = db.take_digest_items(2, SystemTime::UNIX_EPOCH);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].feed_title, "title");
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        assert!(db.take_digest_items(3, now).is_empty());
+        assert_eq!(db.chat(3).digest.unwrap().last_sent, now);
+        assert_eq!(db.digest_subscribers(), vec![3]);
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(gen_hash(""), 0xcbf29ce484222325);
+        assert_eq!(gen_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(gen_hash("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn migrate_hashes() {
+        let path = TempPath::new("rehash", "json");
+        std::fs::write(
+            &path,
+            r#"{"feeds":[{"link":"http://example.com/feed.xml","title":"title","down_time":null,"subscribers":[42],"ttl":null,"hash_list":[1,2,3]}]}"#,
+        )
+        .unwrap();
+        let item = |id: &str| feed::Item {
+            id: Some(id.into()),
+            ..Default::default()
+        };
+        let rss = |items| feed::Rss {
+            title: "title".into(),
+            items,
+            ..Default::default()
+        };
+        let mut db = Database::open(path.clone()).unwrap();
+        let updates = db.update(
+            "http://example.com/feed.xml",
+            rss(vec![item("1")]),
+            None,
+            None,
+        );
+        assert!(updates.is_empty());
+
+        let mut db = Database::open(path.clone()).unwrap();
+        let updates = db.update(
+            "http://example.com/feed.xml",
+            rss(vec![item("2"), item("1")]),
+            None,
+            None,
+        );
+        assert!(matches!(&*updates, [FeedUpdate::Items(items)] if items.len() == 1));
+        assert!(json::backup_path(&path, 0).exists());
+    }
+
+    #[test]
+    fn pause() {
+        let path = TempPath::new("pause", "json");
+        let mut db = Database::create(path.clone()).unwrap();
+        let rss = feed::Rss {
+            title: "title".into(),
+            ..Default::default()
+        };
+        db.subscribe(1, "http://a.com/feed.xml", &rss);
+        db.subscribe(1, "http://b.com/feed.xml", &rss);
+        db.subscribe(2, "http://a.com/feed.xml", &rss);
+        assert_eq!(db.set_paused(1, Some("http://a.com/feed.xml"), true), 1);
+        assert_eq!(db.set_paused(1, Some("http://c.com/feed.xml"), true), 0);
+        assert_eq!(db.set_paused(1, None, true), 1);
+        assert_eq!(db.set_paused(3, None, true), 0);
+
+        let mut db = Database::open(path.clone()).unwrap();
+        let paused = |db: &Database, subscriber| {
+            db.subscribed_feeds(subscriber)
+                .unwrap()
+                .iter()
+                .filter(|feed| feed.paused.contains(&subscriber))
+                .count()
+        };
+        assert_eq!((paused(&db, 1), paused(&db, 2)), (2, 0));
+        db.unsubscribe(1, "http://a.com/feed.xml");
+        db.subscribe(1, "http://a.com/feed.xml", &rss);
+        assert_eq!(paused(&db, 1), 1);
+        assert_eq!(db.set_paused(1, None, false), 1);
+    }
+
+    #[test]
+    fn sqlite_subscriptions() {
+        let path = TempPath::new("subs", "json");
+        let (a, b) = ("http://a.com/feed.xml", "http://b.com/feed.xml");
+        let mut db = Database::open_sqlite(path.clone()).unwrap();
+        db.subscribe(1, a, &feed::Rss::default());
+        db.subscribe(2, a, &feed::Rss::default());
+        db.subscribe(2, b, &feed::Rss::default());
+        db.set_filter(1, a, "+x".parse().unwrap());
+        db.set_paused(2, Some(a), true);
+        // Feed changes keep the subscriptions
+        let rss = feed::Rss {
+            title: "new title".into(),
+            ..Default::default()
+        };
+        db.update(a, rss, None, None);
+        db.postpone([a], SystemTime::now() + Duration::from_secs(60));
+        db.unsubscribe(2, b);
+
+        let mut db = Database::open_sqlite(path.clone()).unwrap();
+        assert!(db.is_subscribed(1, a) && db.is_subscribed(2, a));
+        assert!(db.is_postponed(a));
+        assert!(!db.is_subscribed(2, b));
+        assert_eq!(db.all_feeds().len(), 1);
+        assert_eq!(db.filter(1, a).map(|f| f.to_string()), Some("+x".into()));
+        db.update_subscriber(2, 3);
+        db.unsubscribe(1, a);
+
+        let db = Database::open_sqlite(path.clone()).unwrap();
+        assert!(!db.is_subscribed(1, a) && !db.is_subscribed(2, a));
+        let feeds = db.subscribed_feeds(3).unwrap();
+        assert!(feeds[0].paused.contains(&3));
+    }
+
+    #[test]
+    fn deferred_saves() {
+        let path = TempPath::new("defer", "json");
+        let link = "http://a.com/feed.xml";
+        let mut db = Database::create(path.clone()).unwrap();
+        db.defer_saves();
+        db.subscribe(1, link, &feed::Rss::default());
+        assert!(!Database::open(path.clone()).unwrap().is_subscribed(1, link));
+        db.save_deferred();
+        assert!(Database::open(path.clone()).unwrap().is_subscribed(1, link));
+    }
+
+    #[test]
+    fn edit_items() {
+        let path = TempPath::new("edit", "json");
+        let link = "http://a.com/feed.xml";
+        let item = |id: Option<&str>, title: &str| feed::Item {
+            id: id.map(String::from),
+            title: Some(title.into()),
+            link: Some("http://a.com/1".into()),
+            ..Default::default()
+        };
+        let rss = |items| feed::Rss {
+            title: "title".into(),
+            items,
+            ..Default::default()
+        };
+        let edited = |updates: Vec<FeedUpdate>| match &updates[..] {
+            [FeedUpdate::Edited(items)] => items
+                .iter()
+                .map(|(item, messages)| (item.title.clone().unwrap(), messages.clone()))
+                .collect(),
+            [] => Vec::new(),
+            _ => panic!("not only edits"),
+        };
+        let mut db = Database::create(path.clone()).unwrap();
+        db.subscribe(1, link, &rss(Vec::new()));
+        db.set_chat(
+            1,
+            Chat {
+                edit_messages: true,
+                ..Default::default()
+            },
+        );
+        let key = item_key(&item(Some("1"), "")).unwrap();
+        let message = SentMessage {
+            chat: 1,
+            message_id: 10,
+        };
+
+        let updates = db.update(link, rss(vec![item(Some("1"), "a")]), None, None);
+        assert!(matches!(&updates[..], [FeedUpdate::Items(items)] if items.len() == 1));
+        db.record_sent_message(feed_id(link), key, 1, 10);
+        let updates = db.update(link, rss(vec![item(Some("1"), "b")]), None, None);
+        assert_eq!(edited(updates), vec![("b".into(), vec![message])]);
+        let updates = db.update(link, rss(vec![item(Some("1"), "b")]), None, None);
+        assert_eq!(edited(updates), vec![]);
+
+        // Not in chats that paused the feed
+        db.subscribe(2, link, &rss(Vec::new()));
+        db.record_sent_message(feed_id(link), key, 2, 20);
+        db.set_paused(2, Some(link), true);
+        let updates = db.update(link, rss(vec![item(Some("1"), "paused")]), None, None);
+        assert_eq!(edited(updates), vec![("paused".into(), vec![message])]);
+
+        // Without an id, the item is found by its link and not sent again
+        let mut db = Database::open(path.clone()).unwrap();
+        db.update(link, rss(vec![item(None, "c")]), None, None);
+        let key = item_key(&item(None, "")).unwrap();
+        db.record_sent_message(feed_id(link), key, 1, 11);
+        let updates = db.update(link, rss(vec![item(None, "d")]), None, None);
+        let message = SentMessage {
+            chat: 1,
+            message_id: 11,
+        };
+        assert_eq!(edited(updates), vec![("d".into(), vec![message])]);
+
+        // The records are dropped once no chat edits messages
+        db.set_chat(1, Chat::default());
+        let updates = db.update(link, rss(vec![item(None, "e")]), None, None);
+        assert!(matches!(&updates[..], [FeedUpdate::Items(_)]));
+        assert!(db.sent_items.is_empty());
+    }
+
+    #[test]
+    fn sqlite_storage() {
+        let path = TempPath::new("sqlite", "json");
+        let sqlite_path = path.with_extension("sqlite");
+        let (a, b) = ("http://a.com/feed.xml", "http://b.com/feed.xml");
+        let rss = feed::Rss {
+            title: "title".into(),
+            items: vec![feed::Item {
+                id: Some("1".into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut db = Database::create(path.clone()).unwrap();
+        db.subscribe(1, a, &rss);
+        db.set_filter(1, a, "+a".parse().unwrap());
+        db.set_chat(
+            1,
+            Chat {
+                display_mode: DisplayMode::Detailed,
+                ..Default::default()
+            },
+        );
+
+        // The JSON database is imported on the first start
+        let mut db = Database::open_sqlite(path.clone()).unwrap();
+        assert_eq!(db.filter(1, a).map(|f| f.to_string()), Some("+a".into()));
+        db.subscribe(2, b, &rss);
+        db.set_template(2, b, Some("<b>{title}</b>".parse().unwrap()));
+        db.set_paused(2, None, true);
+        db.record_delivery_failure(2, "error".into());
+        db.record_fetch_error(b, "error".into());
+        db.set_link_preview(2, b, Some(true));
+        db.unsubscribe(1, a);
+        db.update_subscriber(2, 3);
+
+        db = Database::open_sqlite(path.clone()).unwrap();
+        assert!(!db.is_subscribed(1, a));
+        assert!(db.is_subscribed(3, b));
+        assert_eq!(db.chat(1).display_mode, DisplayMode::Detailed);
+        assert_eq!(
+            db.template(3, b).map(|t| t.to_string()),
+            Some("<b>{title}</b>".into())
+        );
+        assert_eq!(db.delivery_failure(3).map(|f| f.count), Some(1));
+        assert_eq!(db.link_preview(3, b), Some(true));
+        let feed = db.subscribed_feeds(3).unwrap().pop().unwrap();
+        assert!(feed.paused.contains(&3));
+        assert_eq!(feed.error_count, 1);
+        // The hash list is kept, the item is not new
+        assert!(db.update(b, rss, None, None).is_empty());
+        drop(db);
+
+        // Back to version 1, before link previews of subscriptions
+        let conn = rusqlite::Connection::open(&sqlite_path).unwrap();
+        conn.execute_batch(
+            "ALTER TABLE subscriptions DROP COLUMN link_preview;
+             DROP TABLE sent_items;
+             PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(conn);
+        let db = Database::open_sqlite(path.clone()).unwrap();
+        assert!(db.is_subscribed(3, b));
+        assert_eq!(db.link_preview(3, b), None);
+        assert!(sqlite::backup_path(&sqlite_path, 1).exists());
+    }
+
     #[test]
     fn size64hasher() {
         let mut h = Size64Hasher::default();
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::{self, json, Value};
+
+use super::{
+    check_version, Change, Chat, Contents, DataError, DeliveryFailure, DigestItem, Feed, FeedId,
+    SentItem, Size64, Storage, SubscriberId, Tables, DATABASE_VERSION,
+};
+
+/// `MIGRATIONS[n]` upgrades the JSON of version `n` to `n + 1`
+const MIGRATIONS: &[fn(&mut Value)] = &[rehash_items, keep_as_is, keep_as_is, keep_as_is];
+const _: () = assert!(MIGRATIONS.len() == DATABASE_VERSION as usize);
+
+/// On-disk format
+#[derive(Serialize)]
+struct DatabaseFile<'a> {
+    version: u32,
+    feeds: Vec<&'a Feed>,
+    chats: &'a HashMap<SubscriberId, Chat, Size64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    delivery_failures: &'a HashMap<SubscriberId, DeliveryFailure, Size64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    digest_items: &'a HashMap<SubscriberId, Vec<DigestItem>, Size64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    sent_items: &'a HashMap<FeedId, Vec<SentItem>, Size64>,
+}
+
+#[derive(Deserialize)]
+struct DatabaseFileOwned {
+    feeds: Vec<Feed>,
+    #[serde(default)]
+    chats: HashMap<SubscriberId, Chat, Size64>,
+    #[serde(default)]
+    delivery_failures: HashMap<SubscriberId, DeliveryFailure, Size64>,
+    #[serde(default)]
+    digest_items: HashMap<SubscriberId, Vec<DigestItem>, Size64>,
+    #[serde(default)]
+    sent_items: HashMap<FeedId, Vec<SentItem>, Size64>,
+}
+
+/// The whole database in one JSON file, rewritten on every change
+#[derive(Debug)]
+pub struct JsonStorage {
+    path: PathBuf,
+    /// The version of the loaded file, if it must be backed up before it's overwritten
+    backup: Option<u32>,
+}
+
+impl JsonStorage {
+    pub fn new(path: PathBuf) -> JsonStorage {
+        JsonStorage { path, backup: None }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self) -> Result<Option<Contents>, DataError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let f = File::open(&self.path)?;
+        let mut content: Value = serde_json::from_reader(&f)?;
+        if content.is_array() {
+            // A bare list of feeds, the format before chat settings were added
+            content = json!({ "feeds": content });
+        }
+        let version = match content.get("version") {
+            Some(version) => u32::deserialize(version)?,
+            None => 0,
+        };
+        check_version(version)?;
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(&mut content);
+        }
+        if version < DATABASE_VERSION {
+            self.backup = Some(version);
+        }
+        let content: DatabaseFileOwned = serde_json::from_value(content)?;
+        Ok(Some(Contents {
+            version,
+            feeds: content.feeds,
+            chats: content.chats,
+            delivery_failures: content.delivery_failures,
+            digest_items: content.digest_items,
+            sent_items: content.sent_items,
+        }))
+    }
+
+    fn save(&mut self, tables: &Tables, changes: &[Change]) -> Result<(), DataError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if let Some(version) = self.backup {
+            let backup_path = backup_path(&self.path, version);
+            eprintln!(
+                "Migrating {} from version {} to {}, the old file is kept at {}",
+                self.path.display(),
+                version,
+                DATABASE_VERSION,
+                backup_path.display()
+            );
+            fs::copy(&self.path, backup_path)?;
+            self.backup = None;
+        }
+        let content = DatabaseFile {
+            version: DATABASE_VERSION,
+            feeds: tables.feeds.values().collect(),
+            chats: tables.chats,
+            delivery_failures: tables.delivery_failures,
+            digest_items: tables.digest_items,
+            sent_items: tables.sent_items,
+        };
+        let file = AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite);
+        file.write(|file| serde_json::to_writer(file, &content))
+            .map_err(|e| match e {
+                atomicwrites::Error::Internal(e) => DataError::Io(e),
+                atomicwrites::Error::User(e) => {
+                    assert!(!e.is_io(), "unreachable code");
+                    DataError::Io(e.into())
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// `rssbot.json` of version 0 is kept as `rssbot.v0.json`
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    path.with_extension(format!("v{}.json", version))
+}
+
+/// Version 0 hashed items with `DefaultHasher`, old hashes can't be converted.
+/// Mark all items as read on the next update,
+/// a few items may be missed but it's better than a flood of duplicates
+fn rehash_items(content: &mut Value) {
+    let feeds = match content.get_mut("feeds").and_then(Value::as_array_mut) {
+        Some(feeds) => feeds,
+        None => return,
+    };
+    for feed in feeds.iter_mut().filter_map(Value::as_object_mut) {
+        feed.insert("hash_list".into(), json!([]));
+        feed.insert("rehash".into(), json!(true));
+    }
+}
+
+/// For versions that only added fields with defaults or still read the older format
+fn keep_as_is(_content: &mut Value) {}
@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, types::Type, Connection, Row, Transaction};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::filter::Filter;
+
+use super::{
+    check_version, Change, Contents, DataError, DeliveryFailure, Feed, FeedId, Size64, Storage,
+    SubscriberId, Tables, DATABASE_VERSION,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS feeds (
+    id INTEGER PRIMARY KEY,
+    link TEXT NOT NULL,
+    title TEXT NOT NULL,
+    ttl INTEGER,
+    hash_list BLOB NOT NULL,
+    rehash INTEGER NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    down_time INTEGER,
+    error_count INTEGER NOT NULL,
+    last_error TEXT,
+    retry_after INTEGER
+);
+CREATE TABLE IF NOT EXISTS subscriptions (
+    feed_id INTEGER NOT NULL,
+    subscriber INTEGER NOT NULL,
+    paused INTEGER NOT NULL,
+    media INTEGER NOT NULL,
+    filter TEXT,
+    template TEXT,
+    link_preview INTEGER,
+    PRIMARY KEY (feed_id, subscriber)
+);
+CREATE TABLE IF NOT EXISTS chats (
+    id INTEGER PRIMARY KEY,
+    settings TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS delivery_failures (
+    chat INTEGER PRIMARY KEY,
+    count INTEGER NOT NULL,
+    since INTEGER NOT NULL,
+    last_error TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS digest_items (
+    chat INTEGER PRIMARY KEY,
+    items TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sent_items (
+    feed_id INTEGER PRIMARY KEY,
+    items TEXT NOT NULL
+);
+";
+
+/// `MIGRATIONS[n - 1]` upgrades the schema of version `n` to `n + 1`,
+/// version 0 is a new database, `SCHEMA` is always the latest
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE subscriptions ADD COLUMN link_preview INTEGER;",
+    "CREATE TABLE IF NOT EXISTS sent_items (feed_id INTEGER PRIMARY KEY, items TEXT NOT NULL);",
+    "-- Timezones in the chat JSON can be zone names, older offsets are read as they are",
+];
+const _: () = assert!(MIGRATIONS.len() == DATABASE_VERSION as usize - 1);
+
+/// Feeds and subscriptions are stored as rows, chat settings, digest and sent items as JSON,
+/// only the changed records are written
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, DataError> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        match version {
+            0 => conn.execute_batch(SCHEMA)?,
+            version if version < DATABASE_VERSION => migrate(&mut conn, path, version)?,
+            // Newer versions are refused by `load`
+            _ => (),
+        }
+        Ok(SqliteStorage { conn })
+    }
+}
+
+/// `rssbot.sqlite` of version 1 is kept as `rssbot.v1.sqlite`
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    path.with_extension(format!("v{}.sqlite", version))
+}
+
+/// Back up the database, then upgrade the schema step by step in one transaction
+fn migrate(conn: &mut Connection, path: &Path, version: u32) -> Result<(), DataError> {
+    let backup_path = backup_path(path, version);
+    eprintln!(
+        "Migrating {} from version {} to {}, the old database is kept at {}",
+        path.display(),
+        version,
+        DATABASE_VERSION,
+        backup_path.display()
+    );
+    // Left by a failed migration, which was rolled back
+    if !backup_path.exists() {
+        conn.execute("VACUUM INTO ?1", [backup_path.to_string_lossy()])?;
+    }
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", DATABASE_VERSION)?;
+    tx.commit()?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<Option<Contents>, DataError> {
+        // Set by the first save, until then the database is new
+        let version: u32 = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version == 0 {
+            return Ok(None);
+        }
+        check_version(version)?;
+
+        let mut feeds: HashMap<FeedId, Feed, Size64> = HashMap::default();
+        let mut stmt = self.conn.prepare(
+            "SELECT id, link, title, ttl, hash_list, rehash, etag, last_modified,
+                    down_time, error_count, last_error, retry_after
+             FROM feeds",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let feed = Feed {
+                link: row.get(1)?,
+                title: row.get(2)?,
+                ttl: row.get(3)?,
+                hash_list: decode_hash_list(&row.get::<_, Vec<u8>>(4)?),
+                rehash: row.get(5)?,
+                etag: row.get(6)?,
+                last_modified: row.get(7)?,
+                down_time: row.get::<_, Option<i64>>(8)?.map(from_unix_nanos),
+                error_count: row.get(9)?,
+                last_error: row.get(10)?,
+                retry_after: row.get::<_, Option<i64>>(11)?.map(from_unix_nanos),
+                ..Default::default()
+            };
+            Ok((row.get::<_, i64>(0)? as FeedId, feed))
+        })?;
+        for row in rows {
+            let (feed_id, feed) = row?;
+            feeds.insert(feed_id, feed);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT feed_id, subscriber, paused, media, filter, template, link_preview
+             FROM subscriptions",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let feed = match feeds.get_mut(&(row.get::<_, i64>(0)? as FeedId)) {
+                Some(feed) => feed,
+                None => continue,
+            };
+            let subscriber: SubscriberId = row.get(1)?;
+            feed.subscribers.insert(subscriber);
+            if row.get(2)? {
+                feed.paused.insert(subscriber);
+            }
+            if row.get(3)? {
+                feed.media.insert(subscriber);
+            }
+            if let Some(filter) = row.get::<_, Option<String>>(4)? {
+                feed.filters
+                    .insert(subscriber, Filter::from_stored(&filter));
+            }
+            if let Some(template) = parse_column(row, 5)? {
+                feed.templates.insert(subscriber, template);
+            }
+            if let Some(link_preview) = row.get(6)? {
+                feed.link_previews.insert(subscriber, link_preview);
+            }
+        }
+
+        let mut contents = Contents {
+            version,
+            feeds: feeds.into_values().collect(),
+            chats: HashMap::default(),
+            delivery_failures: HashMap::default(),
+            digest_items: HashMap::default(),
+            sent_items: HashMap::default(),
+        };
+        let mut stmt = self.conn.prepare("SELECT id, settings FROM chats")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            contents.chats.insert(row.get(0)?, json_column(row, 1)?);
+        }
+        let mut stmt = self
+            .conn
+            .prepare("SELECT chat, count, since, last_error FROM delivery_failures")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let failure = DeliveryFailure {
+                count: row.get(1)?,
+                since: from_unix_nanos(row.get(2)?),
+                last_error: row.get(3)?,
+            };
+            contents.delivery_failures.insert(row.get(0)?, failure);
+        }
+        let mut stmt = self.conn.prepare("SELECT chat, items FROM digest_items")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            contents
+                .digest_items
+                .insert(row.get(0)?, json_column(row, 1)?);
+        }
+        let mut stmt = self.conn.prepare("SELECT feed_id, items FROM sent_items")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            contents
+                .sent_items
+                .insert(row.get::<_, i64>(0)? as FeedId, json_column(row, 1)?);
+        }
+        Ok(Some(contents))
+    }
+
+    fn save(&mut self, tables: &Tables, changes: &[Change]) -> Result<(), DataError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        for change in changes {
+            match *change {
+                Change::All => write_all(&tx, tables)?,
+                Change::Feed(feed_id) => write_feed(&tx, feed_id, tables.feeds.get(&feed_id))?,
+                Change::FeedState(feed_id) => {
+                    if let Some(feed) = tables.feeds.get(&feed_id) {
+                        write_feed_state(&tx, feed_id, feed)?;
+                    }
+                }
+                Change::Subscription(feed_id, subscriber) => {
+                    let feed = tables.feeds.get(&feed_id);
+                    write_subscription(&tx, feed_id, subscriber, feed)?
+                }
+                Change::Chat(chat) => write_json(
+                    &tx,
+                    "chats",
+                    "id",
+                    "settings",
+                    chat,
+                    tables.chats.get(&chat),
+                )?,
+                Change::DeliveryFailure(chat) => {
+                    write_delivery_failure(&tx, chat, tables.delivery_failures.get(&chat))?
+                }
+                Change::DigestItems(chat) => write_json(
+                    &tx,
+                    "digest_items",
+                    "chat",
+                    "items",
+                    chat,
+                    tables.digest_items.get(&chat),
+                )?,
+                Change::SentItems(feed_id) => write_json(
+                    &tx,
+                    "sent_items",
+                    "feed_id",
+                    "items",
+                    feed_id as i64,
+                    tables.sent_items.get(&feed_id),
+                )?,
+            }
+        }
+        tx.pragma_update(None, "user_version", DATABASE_VERSION)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn write_all(tx: &Transaction, tables: &Tables) -> Result<(), DataError> {
+    tx.execute_batch(
+        "DELETE FROM feeds;
+         DELETE FROM subscriptions;
+         DELETE FROM chats;
+         DELETE FROM delivery_failures;
+         DELETE FROM digest_items;
+         DELETE FROM sent_items;",
+    )?;
+    for (feed_id, feed) in tables.feeds {
+        write_feed(tx, *feed_id, Some(feed))?;
+        for subscriber in &feed.subscribers {
+            write_subscription(tx, *feed_id, *subscriber, Some(feed))?;
+        }
+    }
+    for (chat, settings) in tables.chats {
+        write_json(tx, "chats", "id", "settings", *chat, Some(settings))?;
+    }
+    for (chat, failure) in tables.delivery_failures {
+        write_delivery_failure(tx, *chat, Some(failure))?;
+    }
+    for (chat, items) in tables.digest_items {
+        write_json(tx, "digest_items", "chat", "items", *chat, Some(items))?;
+    }
+    for (feed_id, items) in tables.sent_items {
+        let id = *feed_id as i64;
+        write_json(tx, "sent_items", "feed_id", "items", id, Some(items))?;
+    }
+    Ok(())
+}
+
+/// The feed without its subscriptions, or remove both if `feed` is `None`
+fn write_feed(tx: &Transaction, feed_id: FeedId, feed: Option<&Feed>) -> Result<(), DataError> {
+    let id = feed_id as i64;
+    let feed = match feed {
+        Some(feed) => feed,
+        None => {
+            tx.prepare_cached("DELETE FROM subscriptions WHERE feed_id = ?1")?
+                .execute([id])?;
+            tx.prepare_cached("DELETE FROM feeds WHERE id = ?1")?
+                .execute([id])?;
+            return Ok(());
+        }
+    };
+    tx.prepare_cached(
+        "INSERT OR REPLACE INTO feeds
+         (id, link, title, ttl, hash_list, rehash, etag, last_modified,
+          down_time, error_count, last_error, retry_after)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?
+    .execute(params![
+        id,
+        feed.link,
+        feed.title,
+        feed.ttl,
+        encode_hash_list(&feed.hash_list),
+        feed.rehash,
+        feed.etag,
+        feed.last_modified,
+        feed.down_time.map(to_unix_nanos),
+        feed.error_count,
+        feed.last_error,
+        feed.retry_after.map(to_unix_nanos),
+    ])?;
+    Ok(())
+}
+
+/// The settings of a subscription, or remove it if `feed` is `None` or not subscribed
+fn write_subscription(
+    tx: &Transaction,
+    feed_id: FeedId,
+    subscriber: SubscriberId,
+    feed: Option<&Feed>,
+) -> Result<(), DataError> {
+    let id = feed_id as i64;
+    let feed = match feed {
+        Some(feed) if feed.subscribers.contains(&subscriber) => feed,
+        _ => {
+            tx.prepare_cached("DELETE FROM subscriptions WHERE feed_id = ?1 AND subscriber = ?2")?
+                .execute(params![id, subscriber])?;
+            return Ok(());
+        }
+    };
+    tx.prepare_cached(
+        "INSERT OR REPLACE INTO subscriptions
+         (feed_id, subscriber, paused, media, filter, template, link_preview)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?
+    .execute(params![
+        id,
+        subscriber,
+        feed.paused.contains(&subscriber),
+        feed.media.contains(&subscriber),
+        feed.filters
+            .get(&subscriber)
+            .map(|filter| filter.to_string()),
+        feed.templates
+            .get(&subscriber)
+            .map(|template| template.to_string()),
+        feed.link_previews.get(&subscriber),
+    ])?;
+    Ok(())
+}
+
+/// Only the columns changed by fetching, the subscriptions are left as is
+fn write_feed_state(tx: &Transaction, feed_id: FeedId, feed: &Feed) -> Result<(), DataError> {
+    tx.prepare_cached(
+        "UPDATE feeds SET down_time = ?2, error_count = ?3, last_error = ?4, retry_after = ?5
+         WHERE id = ?1",
+    )?
+    .execute(params![
+        feed_id as i64,
+        feed.down_time.map(to_unix_nanos),
+        feed.error_count,
+        feed.last_error,
+        feed.retry_after.map(to_unix_nanos),
+    ])?;
+    Ok(())
+}
+
+fn write_delivery_failure(
+    tx: &Transaction,
+    chat: SubscriberId,
+    failure: Option<&DeliveryFailure>,
+) -> Result<(), DataError> {
+    match failure {
+        Some(failure) => {
+            tx.prepare_cached(
+                "INSERT OR REPLACE INTO delivery_failures (chat, count, since, last_error)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![
+                chat,
+                failure.count,
+                to_unix_nanos(failure.since),
+                failure.last_error
+            ])?;
+        }
+        None => {
+            tx.prepare_cached("DELETE FROM delivery_failures WHERE chat = ?1")?
+                .execute([chat])?;
+        }
+    }
+    Ok(())
+}
+
+/// A record stored as JSON in `column`, or remove it if `value` is `None`
+fn write_json<T: Serialize>(
+    tx: &Transaction,
+    table: &str,
+    key: &str,
+    column: &str,
+    id: i64,
+    value: Option<&T>,
+) -> Result<(), DataError> {
+    match value {
+        Some(value) => {
+            let sql = format!(
+                "INSERT OR REPLACE INTO {} ({}, {}) VALUES (?1, ?2)",
+                table, key, column
+            );
+            tx.prepare_cached(&sql)?
+                .execute(params![id, serde_json::to_string(value)?])?;
+        }
+        None => {
+            let sql = format!("DELETE FROM {} WHERE {} = ?1", table, key);
+            tx.prepare_cached(&sql)?.execute([id])?;
+        }
+    }
+    Ok(())
+}
+
+fn json_column<T: DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn parse_column<T>(row: &Row, idx: usize) -> rusqlite::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let text: Option<String> = row.get(idx)?;
+    text.map(|text| text.parse())
+        .transpose()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn encode_hash_list(hash_list: &[u64]) -> Vec<u8> {
+    hash_list
+        .iter()
+        .flat_map(|hash| hash.to_le_bytes())
+        .collect()
+}
+
+fn decode_hash_list(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+fn to_unix_nanos(time: SystemTime) -> i64 {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_nanos() as i64
+}
+
+fn from_unix_nanos(nanos: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64)
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use tokio::{self, sync::Mutex, time};
+
+use crate::data::{Database, Digest, DigestItem};
+use crate::i18n;
+use crate::messages::{format_large_msg, Escape};
+use crate::outbox::Outbox;
+use crate::timezone::{format_time_of_day, parse_time_of_day, Timezone};
+
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Send the digests when they are due, items of chats without a schedule
+/// (f.e. the digest was just turned off) are sent immediately
+pub fn start(db: Arc<Mutex<Database>>, outbox: Arc<Mutex<Outbox>>) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush_due(&db, &outbox, SystemTime::now()).await;
+        }
+    });
+}
+
+async fn flush_due(db: &Mutex<Database>, outbox: &Mutex<Outbox>, now: SystemTime) {
+    let mut db = db.lock().await;
+    let mut outbox = outbox.lock().await;
+    // The items are removed from the database only after they're in the outbox
+    db.defer_saves();
+    for subscriber in db.digest_subscribers() {
+        let chat = db.chat(subscriber);
+        let due = match chat.digest {
+            Some(digest) => last_due(&digest, now) > digest.last_sent,
+            None => true,
+        };
+        if !due {
+            continue;
+        }
+        let items = db.take_digest_items(subscriber, now);
+        if items.is_empty() {
+            continue;
+        }
+        for msg in i18n::with(chat.lang(), || format_digest(&items)) {
+            outbox.push(Some(subscriber), &msg);
+        }
+    }
+    outbox.flush_or_log();
+    db.save_deferred();
+}
+
+/// The latest scheduled time not after `now`
+fn last_due(digest: &Digest, now: SystemTime) -> SystemTime {
+    let local = digest.timezone.to_local(DateTime::<Utc>::from(now));
+    let time =
+        NaiveTime::from_num_seconds_from_midnight_opt(digest.minute_of_day % (24 * 60) * 60, 0)
+            .unwrap();
+    let mut due = local.date().and_time(time);
+    if due > local {
+        due -= Duration::days(1);
+    }
+    if let Some(weekday) = digest.weekday {
+        while due.weekday().num_days_from_monday() != weekday {
+            due -= Duration::days(1);
+        }
+    }
+    SystemTime::from(digest.timezone.to_utc(due))
+}
+
+/// Items are grouped by feed, in the order they were received
+fn format_digest(items: &[DigestItem]) -> Vec<String> {
+    let mut groups: Vec<(&DigestItem, Vec<&DigestItem>)> = Vec::new();
+    for item in items {
+        match groups
+            .iter_mut()
+            .find(|(first, _)| first.feed_link == item.feed_link)
+        {
+            Some((_, group)) => group.push(item),
+            None => groups.push((item, vec![item])),
+        }
+    }
+    let mut lines = Vec::new();
+    for (first, group) in groups {
+        lines.push(format!(
+            "\n<b><a href=\"{}\">{}</a></b>",
+            Escape(&first.feed_link),
+            Escape(&first.feed_title)
+        ));
+        for item in group {
+            let title = item.title.as_deref().unwrap_or(&item.feed_title);
+            let link = item.link.as_deref().unwrap_or(&item.feed_link);
+            lines.push(format!(
+                "<a href=\"{}\">{}</a>",
+                Escape(link),
+                Escape(title)
+            ));
+        }
+    }
+    format_large_msg(tr!("digest_head").to_string(), &lines, |line| line.clone())
+}
+
+/// Parse `[weekday] HH:MM [timezone]`, f.e. `09:00`, `mon 18:30 Asia/Shanghai`,
+/// `default_timezone` is used if the timezone is omitted
+pub fn parse_schedule(
+    args: &[&str],
+    default_timezone: Timezone,
+    now: SystemTime,
+) -> Option<Digest> {
+    let (weekday, args) = match args.split_first() {
+        Some((day, rest)) if day.parse::<Weekday>().is_ok() => {
+            (Some(day.parse::<Weekday>().unwrap()), rest)
+        }
+        _ => (None, args),
+    };
+    let (time, timezone) = match args {
+        [time] => (time, default_timezone),
+        [time, timezone] => (time, timezone.parse().ok()?),
+        _ => return None,
+    };
+    Some(Digest {
+        minute_of_day: parse_time_of_day(time)?,
+        timezone,
+        weekday: weekday.map(|day| day.num_days_from_monday()),
+        // Don't send items collected before the schedule was set up right away
+        last_sent: now,
+    })
+}
+
+/// The same format as `parse_schedule` accepts
+pub fn format_schedule(digest: &Digest) -> String {
+    let time = format!(
+        "{} {}",
+        format_time_of_day(digest.minute_of_day),
+        digest.timezone
+    );
+    match digest.weekday {
+        Some(day) => {
+            let day = (0..day % 7).fold(Weekday::Mon, |day, _| day.succ());
+            format!("{} {}", day, time)
+        }
+        None => time,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(s: &str) -> SystemTime {
+        SystemTime::from(DateTime::parse_from_rfc3339(s).unwrap())
+    }
+
+    #[test]
+    fn schedule() {
+        let now = at("2023-09-06T12:00:00Z"); // Wednesday
+        let digest = parse_schedule(&["09:30", "+08:00"], Timezone::UTC, now).unwrap();
+        assert_eq!(format_schedule(&digest), "09:30 UTC+08:00");
+        assert_eq!(last_due(&digest, now), at("2023-09-06T01:30:00Z"));
+        assert_eq!(
+            last_due(&digest, at("2023-09-06T01:29:00Z")),
+            at("2023-09-05T01:30:00Z")
+        );
+
+        let digest = parse_schedule(&["mon", "18:00", "UTC-5"], Timezone::UTC, now).unwrap();
+        assert_eq!(format_schedule(&digest), "Mon 18:00 UTC-05:00");
+        assert_eq!(last_due(&digest, now), at("2023-09-04T23:00:00Z"));
+
+        let digest = parse_schedule(&["09:30"], Timezone::Fixed(3600), now).unwrap();
+        assert_eq!(format_schedule(&digest), "09:30 UTC+01:00");
+
+        // Follows daylight saving time
+        let digest = parse_schedule(&["09:30", "Europe/Berlin"], Timezone::UTC, now).unwrap();
+        assert_eq!(format_schedule(&digest), "09:30 Europe/Berlin");
+        assert_eq!(last_due(&digest, now), at("2023-09-06T07:30:00Z"));
+        assert_eq!(
+            last_due(&digest, at("2023-12-06T12:00:00Z")),
+            at("2023-12-06T08:30:00Z")
+        );
+        // Skipped by the clocks, passed once they jump
+        let digest = parse_schedule(&["02:30", "Europe/Berlin"], Timezone::UTC, now).unwrap();
+        assert_eq!(
+            last_due(&digest, at("2023-03-26T00:59:00Z")),
+            at("2023-03-25T01:30:00Z")
+        );
+        assert_eq!(
+            last_due(&digest, at("2023-03-26T01:00:00Z")),
+            at("2023-03-26T00:30:00Z")
+        );
+
+        assert_eq!(parse_schedule(&["25:00"], Timezone::UTC, now), None);
+        assert_eq!(parse_schedule(&["09:00", "8"], Timezone::UTC, now), None);
+        assert_eq!(
+            parse_schedule(&["09:00", "Europe/Nowhere"], Timezone::UTC, now),
+            None
+        );
+        assert_eq!(parse_schedule(&["mon"], Timezone::UTC, now), None);
+    }
+
+    #[test]
+    fn group_by_feed() {
+        let item = |feed: &str, title: &str| DigestItem {
+            feed_link: format!("http://{}.com/feed.xml", feed),
+            feed_title: feed.into(),
+            title: Some(title.into()),
+            link: None,
+        };
+        let msgs = format_digest(&[item("a", "1"), item("b", "2"), item("a", "3")]);
+        assert_eq!(msgs.len(), 1);
+        let titles: Vec<&str> = msgs[0]
+            .lines()
+            .skip(1)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.trim_end_matches("</b>").trim_end_matches("</a>"))
+            .filter_map(|line| line.rsplit('>').next())
+            .collect();
+        assert_eq!(titles, vec!["a", "1", "3", "b", "2"]);
+    }
+}
@@ -5,6 +5,7 @@ use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::str;
 
+use chrono::{DateTime, FixedOffset};
 use lazy_static::lazy_static;
 use quick_xml::events::attributes::Attributes;
 use quick_xml::events::BytesStart;
@@ -13,6 +14,8 @@ use quick_xml::Reader as XmlReader;
 use regex::Regex;
 use serde::Deserialize;
 
+use crate::messages::{decode_entities, Escape};
+
 trait FromXml: Sized {
     fn from_xml<B: std::io::BufRead>(
         bufs: &BufPool,
@@ -255,11 +258,189 @@ impl FromXml for Rss {
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "JsonItem")]
 pub struct Item {
     pub title: Option<String>,
-    #[serde(rename = "url")]
     pub link: Option<String>,
     pub id: Option<String>,
+    /// `description` of RSS or `summary` of Atom, may contain HTML
+    pub summary: Option<String>,
+    /// `content:encoded` of RSS or `content` of Atom, may contain HTML
+    pub content: Option<String>,
+    pub author: Option<String>,
+    /// Publish (or update if unavailable) date, as is
+    pub date: Option<String>,
+    /// `<enclosure>`, `media:content` and JSON Feed `attachments`,
+    /// `media:thumbnail` only if there's nothing else
+    pub enclosures: Vec<Enclosure>,
+    /// `<category>` of RSS, `term` of Atom `<category>` or `tags` of JSON Feed
+    pub categories: Vec<String>,
+}
+
+/// A file attached to an item
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Enclosure {
+    pub url: String,
+    /// If only the `medium` of `media:content` is known, it's `{medium}/*`
+    pub mime_type: Option<String>,
+    /// In bytes
+    pub length: Option<u64>,
+}
+
+/// The `term` attribute of Atom `<category>`
+fn parse_atom_category<B: std::io::BufRead>(
+    reader: &mut XmlReader<B>,
+    e: &BytesStart,
+) -> quick_xml::Result<Option<String>> {
+    for attribute in e.attributes() {
+        let attribute = attribute?;
+        if &*reader.decode(attribute.key) == "term" {
+            return Ok(Some(attribute.unescape_and_decode_value(reader)?));
+        }
+    }
+    Ok(None)
+}
+
+/// Parse `<enclosure>`, `<media:content>`, `<media:thumbnail>` or Atom `<link rel="enclosure">`
+fn parse_enclosure<B: std::io::BufRead>(
+    reader: &mut XmlReader<B>,
+    e: &BytesStart,
+) -> quick_xml::Result<Option<Enclosure>> {
+    let name = reader.decode(e.name()).into_owned();
+    let mut enclosure = Enclosure::default();
+    let mut medium = None;
+    let mut rel = None;
+    for attribute in e.attributes() {
+        let attribute = attribute?;
+        let value = attribute.unescape_and_decode_value(reader)?;
+        match &*reader.decode(attribute.key) {
+            "url" | "href" => enclosure.url = value,
+            "type" => enclosure.mime_type = Some(value),
+            "length" | "fileSize" => enclosure.length = value.trim().parse().ok(),
+            "medium" => medium = Some(value),
+            "rel" => rel = Some(value),
+            _ => (),
+        }
+    }
+    if name == "link" && rel.as_deref() != Some("enclosure") {
+        return Ok(None);
+    }
+    if name == "media:thumbnail" {
+        medium = Some("image".into());
+    }
+    if enclosure.mime_type.is_none() {
+        enclosure.mime_type = medium.map(|medium| format!("{}/*", medium));
+    }
+    Ok(Some(enclosure).filter(|enclosure| !enclosure.url.is_empty()))
+}
+
+impl Item {
+    pub fn parsed_date(&self) -> Option<DateTime<FixedOffset>> {
+        let date = self.date.as_deref()?.trim();
+        DateTime::parse_from_rfc2822(date)
+            .or_else(|_| DateTime::parse_from_rfc3339(date))
+            .ok()
+    }
+}
+
+/// https://www.jsonfeed.org/version/1.1/#items-a-name-items-a
+#[derive(Deserialize)]
+struct JsonItem {
+    id: Option<String>,
+    url: Option<String>,
+    title: Option<String>,
+    content_html: Option<String>,
+    content_text: Option<String>,
+    summary: Option<String>,
+    date_published: Option<String>,
+    date_modified: Option<String>,
+    // version 1
+    author: Option<JsonAuthor>,
+    // version 1.1
+    #[serde(default)]
+    authors: Vec<JsonAuthor>,
+    #[serde(default)]
+    attachments: Vec<JsonAttachment>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JsonAuthor {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JsonAttachment {
+    url: String,
+    mime_type: Option<String>,
+    size_in_bytes: Option<u64>,
+}
+
+impl From<JsonItem> for Item {
+    fn from(item: JsonItem) -> Self {
+        Item {
+            title: item.title,
+            link: item.url,
+            id: item.id,
+            summary: item.summary,
+            content: item
+                .content_html
+                .or_else(|| item.content_text.map(|s| Escape(&s).to_string())),
+            author: item
+                .authors
+                .into_iter()
+                .chain(item.author)
+                .find_map(|author| author.name),
+            date: item.date_published.or(item.date_modified),
+            enclosures: item
+                .attachments
+                .into_iter()
+                .map(|attachment| Enclosure {
+                    url: attachment.url,
+                    mime_type: attachment.mime_type,
+                    length: attachment.size_in_bytes,
+                })
+                .collect(),
+            categories: item.tags,
+        }
+    }
+}
+
+/// The `name` of Atom `<author>`, or the text of RSS `<author>` and `<dc:creator>`
+struct Person(Option<String>);
+
+impl FromXml for Person {
+    fn from_xml<B: std::io::BufRead>(
+        bufs: &BufPool,
+        reader: &mut XmlReader<B>,
+        _start: &BytesStart,
+    ) -> quick_xml::Result<Self> {
+        let mut buf = bufs.pop();
+        let mut name = None;
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(XmlEvent::Start(ref e)) => {
+                    if reader.decode(e.local_name()) == "name" {
+                        name = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                    } else {
+                        SkipThisElement::from_xml(bufs, reader, e)?;
+                    }
+                }
+                Ok(XmlEvent::Text(ref e)) => {
+                    name = Some(e.unescape_and_decode(reader)?);
+                }
+                Ok(XmlEvent::CData(ref e)) => {
+                    name = Some(reader.decode(e).to_string());
+                }
+                Ok(XmlEvent::End(_)) | Ok(XmlEvent::Eof) => break,
+                Err(err) => return Err(err.into()),
+                _ => (),
+            }
+            buf.clear();
+        }
+        Ok(Person(name))
+    }
 }
 
 impl FromXml for Item {
@@ -270,17 +451,28 @@ impl FromXml for Item {
     ) -> quick_xml::Result<Self> {
         let mut buf = bufs.pop();
         let mut item = Item::default();
+        let mut updated = None;
+        let mut thumbnails = Vec::new();
+        let mut media_group_depth = 0;
         loop {
             match reader.read_event(&mut buf) {
-                Ok(XmlEvent::Empty(ref e)) => {
-                    if reader.decode(e.name()) == "link" {
+                Ok(XmlEvent::Empty(ref e)) => match &*reader.decode(e.name()) {
+                    "link" => {
                         if let Some(AtomLink::Alternate(link)) =
                             parse_atom_link(reader, e.attributes())?
                         {
                             item.link = Some(link);
+                        } else if let Some(enclosure) = parse_enclosure(reader, e)? {
+                            item.enclosures.push(enclosure);
                         }
                     }
-                }
+                    "enclosure" | "media:content" => {
+                        item.enclosures.extend(parse_enclosure(reader, e)?);
+                    }
+                    "media:thumbnail" => thumbnails.extend(parse_enclosure(reader, e)?),
+                    "category" => item.categories.extend(parse_atom_category(reader, e)?),
+                    _ => (),
+                },
                 Ok(XmlEvent::Start(ref e)) => {
                     match &*reader.decode(e.name()) {
                         "title" => {
@@ -302,17 +494,63 @@ impl FromXml for Item {
                         "id" | "guid" => {
                             item.id = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
                         }
+                        "description" | "summary" => {
+                            item.summary = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                        }
+                        "content:encoded" | "content" => {
+                            let content = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                            // only the first one
+                            item.content = item.content.take().or(content);
+                        }
+                        "author" | "dc:creator" => {
+                            let Person(author) = Person::from_xml(bufs, reader, e)?;
+                            item.author = item.author.take().or(author);
+                        }
+                        "pubDate" | "published" | "issued" | "dc:date" => {
+                            item.date = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                        }
+                        "updated" | "modified" => {
+                            updated = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                        }
+                        "enclosure" | "media:content" => {
+                            item.enclosures.extend(parse_enclosure(reader, e)?);
+                            SkipThisElement::from_xml(bufs, reader, e)?;
+                        }
+                        "media:thumbnail" => {
+                            thumbnails.extend(parse_enclosure(reader, e)?);
+                            SkipThisElement::from_xml(bufs, reader, e)?;
+                        }
+                        "category" => {
+                            let term = parse_atom_category(reader, e)?;
+                            let text = <Option<String> as FromXml>::from_xml(bufs, reader, e)?;
+                            item.categories.extend(text.or(term));
+                        }
+                        "media:group" => {
+                            // Parse the children as if they were in the item
+                            media_group_depth += 1;
+                        }
                         _ => {
                             SkipThisElement::from_xml(bufs, reader, e)?;
                         }
                     }
                 }
+                Ok(XmlEvent::End(_)) if media_group_depth > 0 => media_group_depth -= 1,
                 Ok(XmlEvent::End(_)) | Ok(XmlEvent::Eof) => break,
                 Err(err) => return Err(err.into()),
                 _ => (),
             }
             buf.clear();
         }
+        if item.date.is_none() {
+            item.date = updated;
+        }
+        if item.enclosures.is_empty() {
+            item.enclosures = thumbnails;
+        }
+        // `<enclosure>` and `media:content` often point to the same file
+        let mut urls = std::collections::HashSet::new();
+        item.enclosures
+            .retain(|enclosure| urls.insert(enclosure.url.clone()));
         Ok(item)
     }
 }
@@ -416,11 +654,59 @@ pub fn fix_relative_url(mut rss: Rss, rss_link: &str) -> Rss {
         if let Some(link) = item.link.as_mut() {
             url_relative_to_absolute(link, rss_host);
         }
+        for enclosure in &mut item.enclosures {
+            url_relative_to_absolute(&mut enclosure.url, rss_host);
+        }
     }
 
     rss
 }
 
+/// Find feeds advertised by `<link rel="alternate">` in an HTML page,
+/// return the `href`s as they are, they may be relative
+pub fn discover_feeds(html: &str) -> Vec<String> {
+    lazy_static! {
+        static ref LINK: Regex = Regex::new(r"(?i)<link\b([^>]*)>").unwrap();
+        static ref ATTR: Regex =
+            Regex::new(r#"([a-zA-Z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+    }
+    const FEED_TYPES: [&str; 4] = [
+        "application/rss+xml",
+        "application/atom+xml",
+        "application/feed+json",
+        "application/rdf+xml",
+    ];
+    let mut feeds = Vec::new();
+    for link in LINK.captures_iter(html) {
+        let (mut rel, mut mime, mut href) = ("", "", "");
+        for attr in ATTR.captures_iter(&link[1]) {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .or_else(|| attr.get(4))
+                .map_or("", |m| m.as_str());
+            match &*attr[1].to_ascii_lowercase() {
+                "rel" => rel = value,
+                "type" => mime = value,
+                "href" => href = value.trim(),
+                _ => (),
+            }
+        }
+        let is_alternate = rel
+            .split_whitespace()
+            .any(|rel| rel.eq_ignore_ascii_case("alternate"));
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+        let is_feed = FEED_TYPES.iter().any(|t| t.eq_ignore_ascii_case(mime));
+        if is_alternate && is_feed && !href.is_empty() {
+            let href = decode_entities(href);
+            if !feeds.contains(&href) {
+                feeds.push(href);
+            }
+        }
+    }
+    feeds
+}
+
 struct BufPool {
     pool: Rc<RefCell<Vec<Vec<u8>>>>,
     capacity: usize,
@@ -478,6 +764,25 @@ mod test {
 
     use super::*;
 
+    fn rss_enclosures(version: &str, item: usize, count: usize) -> Vec<Enclosure> {
+        (0..count)
+            .map(|i| Enclosure {
+                url: format!("{}.channel.item[{}].enclousure[{}]^url", version, item, i),
+                mime_type: Some(format!(
+                    "{}.channel.item[{}].enclousure[{}]^type",
+                    version, item, i
+                )),
+                length: Some(100),
+            })
+            .collect()
+    }
+
+    fn rss_categories(version: &str, item: usize) -> Vec<String> {
+        (0..2)
+            .map(|i| format!("{}.channel.item[{}].category[{}]", version, item, i))
+            .collect()
+    }
+
     #[test]
     fn encoding() {
         let s: &[u8] = &*include_bytes!("../tests/data/encoding.xml");
@@ -499,11 +804,23 @@ mod test {
                         title: Some("atom_0.3.feed.entry[0].title".into()),
                         link: Some("atom_0.3.feed.entry[0].link^href".into()),
                         id: Some("atom_0.3.feed.entry[0]^id".into()),
+                        summary: Some("atom_0.3.feed.entry[0].summary".into()),
+                        content: Some("atom_0.3.feed.entry[0].content[0]".into()),
+                        author: Some("atom_0.3.feed.entry[0].author.name".into()),
+                        date: Some("2000-01-01T01:00:00Z".into()),
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                     Item {
                         title: Some("atom_0.3.feed.entry[1].title".into()),
                         link: Some("atom_0.3.feed.entry[1].link^href".into()),
                         id: Some("atom_0.3.feed.entry[1]^id".into()),
+                        summary: Some("atom_0.3.feed.entry[1].summary".into()),
+                        content: Some("atom_0.3.feed.entry[1].content[0]".into()),
+                        author: Some("atom_0.3.feed.entry[1].author.name".into()),
+                        date: Some("2000-02-01T01:00:00Z".into()),
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                 ],
                 ..Rss::default()
@@ -526,11 +843,31 @@ mod test {
                         title: Some("atom_1.0.feed.entry[0].title".into()),
                         link: Some("http://example.com/blog/entry1_plain".into()),
                         id: Some("atom_1.0.feed.entry[0]^id".into()),
+                        summary: Some("atom_1.0.feed.entry[0].summary".into()),
+                        content: Some("atom_1.0.feed.entry[0].content[0]".into()),
+                        author: Some("atom_1.0.feed.entry[0].author.name".into()),
+                        date: Some("2000-01-01T01:00:00Z".into()),
+                        enclosures: vec![Enclosure {
+                            url: "http://example.com/blog/enclosure1.gif".into(),
+                            mime_type: Some("image/gif".into()),
+                            length: None,
+                        }],
+                        categories: vec![],
                     },
                     Item {
                         title: Some("atom_1.0.feed.entry[1].title".into()),
                         link: Some("http://example.com/blog/entry2".into()),
                         id: Some("atom_1.0.feed.entry[1]^id".into()),
+                        summary: Some("atom_1.0.feed.entry[1].summary".into()),
+                        content: Some("atom_1.0.feed.entry[1].content[0]".into()),
+                        author: Some("atom_1.0.feed.entry[1].author.name".into()),
+                        date: Some("2000-02-01T01:00:00Z".into()),
+                        enclosures: vec![Enclosure {
+                            url: "http://example.com/blog/enclosure2.gif".into(),
+                            mime_type: Some("image/gif".into()),
+                            length: None,
+                        }],
+                        categories: vec![],
                     },
                 ],
                 ..Rss::default()
@@ -552,11 +889,23 @@ mod test {
                         title: Some("rss_0.9.item[0].title".into()),
                         link: Some("rss_0.9.item[0].link".into()),
                         id: None,
+                        summary: None,
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                     Item {
                         title: Some("rss_0.9.item[1].title".into()),
                         link: Some("rss_0.9.item[1].link".into()),
                         id: None,
+                        summary: None,
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                 ],
                 ..Rss::default()
@@ -578,11 +927,23 @@ mod test {
                         title: Some("rss_0.91.channel.item[0].title".into()),
                         link: Some("rss_0.91.channel.item[0].link".into()),
                         id: None,
+                        summary: Some("rss_0.91.channel.item[0].description".into()),
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                     Item {
                         title: Some("rss_0.91.channel.item[1].title".into()),
                         link: Some("rss_0.91.channel.item[1].link".into()),
                         id: None,
+                        summary: Some("rss_0.91.channel.item[1].description".into()),
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                 ],
                 ..Rss::default()
@@ -604,11 +965,23 @@ mod test {
                         title: Some("rss_0.92.channel.item[0].title".into()),
                         link: Some("rss_0.92.channel.item[0].link".into()),
                         id: None,
+                        summary: Some("rss_0.92.channel.item[0].description".into()),
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: rss_enclosures("rss_0.92", 0, 1),
+                        categories: rss_categories("rss_0.92", 0),
                     },
                     Item {
                         title: Some("rss_0.92.channel.item[1].title".into()),
                         link: Some("rss_0.92.channel.item[1].link".into()),
                         id: None,
+                        summary: Some("rss_0.92.channel.item[1].description".into()),
+                        content: None,
+                        author: None,
+                        date: None,
+                        enclosures: rss_enclosures("rss_0.92", 1, 1),
+                        categories: rss_categories("rss_0.92", 1),
                     },
                 ],
                 ..Rss::default()
@@ -630,11 +1003,23 @@ mod test {
                         title: Some("rss_0.93.channel.item[0].title".into()),
                         link: Some("rss_0.93.channel.item[0].link".into()),
                         id: None,
+                        summary: Some("rss_0.93.channel.item[0].description".into()),
+                        content: None,
+                        author: None,
+                        date: Some("Mon, 01 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_0.93", 0, 2),
+                        categories: rss_categories("rss_0.93", 0),
                     },
                     Item {
                         title: Some("rss_0.93.channel.item[1].title".into()),
                         link: Some("rss_0.93.channel.item[1].link".into()),
                         id: None,
+                        summary: Some("rss_0.93.channel.item[1].description".into()),
+                        content: None,
+                        author: None,
+                        date: Some("Tue, 02 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_0.93", 1, 2),
+                        categories: rss_categories("rss_0.93", 1),
                     },
                 ],
                 ..Rss::default()
@@ -657,11 +1042,23 @@ mod test {
                         title: Some("rss_0.94.channel.item[0].title".into()),
                         link: Some("rss_0.94.channel.item[0].link".into()),
                         id: Some("rss_0.94.channel.item[0].guid".into()),
+                        summary: Some("rss_0.94.channel.item[0].description".into()),
+                        content: None,
+                        author: Some("rss_0.94.channel.item[0].author".into()),
+                        date: Some("Mon, 01 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_0.94", 0, 2),
+                        categories: rss_categories("rss_0.94", 0),
                     },
                     Item {
                         title: Some("rss_0.94.channel.item[1].title".into()),
                         link: Some("rss_0.94.channel.item[1].link".into()),
                         id: Some("rss_0.94.channel.item[1].guid".into()),
+                        summary: Some("rss_0.94.channel.item[1].description".into()),
+                        content: None,
+                        author: Some("rss_0.94.channel.item[1].author".into()),
+                        date: Some("Mon, 02 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_0.94", 1, 2),
+                        categories: rss_categories("rss_0.94", 1),
                     },
                 ],
                 ..Rss::default()
@@ -683,11 +1080,23 @@ mod test {
                         title: Some("rss_1.0.item[0].title".into()),
                         link: Some("rss_1.0.item[0].link".into()),
                         id: None,
+                        summary: Some("rss_1.0.item[0].description".into()),
+                        content: Some("rss_1.0.item[0].content".into()),
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                     Item {
                         title: Some("rss_1.0.item[1].title".into()),
                         link: Some("rss_1.0.item[1].link".into()),
                         id: None,
+                        summary: Some("rss_1.0.item[1].description".into()),
+                        content: Some("rss_1.0.item[1].content".into()),
+                        author: None,
+                        date: None,
+                        enclosures: vec![],
+                        categories: vec![],
                     },
                 ],
                 ..Rss::default()
@@ -710,11 +1119,23 @@ mod test {
                         title: Some("rss_2.0.channel.item[0].title".into()),
                         link: Some("rss_2.0.channel.item[0].link".into()),
                         id: Some("rss_2.0.channel.item[0].guid".into()),
+                        summary: Some("rss_2.0.channel.item[0].description".into()),
+                        content: Some("rss_2.0.channel.item[0].content".into()),
+                        author: Some("rss_2.0.channel.item[0].author".into()),
+                        date: Some("Mon, 01 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_2.0", 0, 2),
+                        categories: rss_categories("rss_2.0", 0),
                     },
                     Item {
                         title: Some("rss_2.0.channel.item[1].title".into()),
                         link: Some("rss_2.0.channel.item[1].link".into()),
                         id: Some("rss_2.0.channel.item[1].guid".into()),
+                        summary: Some("rss_2.0.channel.item[1].description".into()),
+                        content: Some("rss_2.0.channel.item[1].content".into()),
+                        author: Some("rss_2.0.channel.item[1].author".into()),
+                        date: Some("Mon, 02 Jan 2001 00:00:00 GMT".into()),
+                        enclosures: rss_enclosures("rss_2.0", 1, 2),
+                        categories: rss_categories("rss_2.0", 1),
                     },
                 ],
                 ..Rss::default()
@@ -849,4 +1270,162 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn json_feed() {
+        let input = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "json.title",
+            "home_page_url": "https://example.com/",
+            "items": [
+                {
+                    "id": "1",
+                    "url": "https://example.com/1",
+                    "title": "json.item[0].title",
+                    "content_html": "<p>json.item[0].content</p>",
+                    "summary": "json.item[0].summary",
+                    "date_published": "2010-02-07T14:04:00-05:00",
+                    "authors": [{ "name": "json.item[0].author" }]
+                },
+                {
+                    "id": "2",
+                    "content_text": "a < b",
+                    "date_modified": "2010-02-08T14:04:00-05:00",
+                    "author": { "name": "json.item[1].author" }
+                }
+            ]
+        }"#;
+        let r: Rss = serde_json::from_str(input).unwrap();
+        assert_eq!(
+            r.items,
+            vec![
+                Item {
+                    title: Some("json.item[0].title".into()),
+                    link: Some("https://example.com/1".into()),
+                    id: Some("1".into()),
+                    summary: Some("json.item[0].summary".into()),
+                    content: Some("<p>json.item[0].content</p>".into()),
+                    author: Some("json.item[0].author".into()),
+                    date: Some("2010-02-07T14:04:00-05:00".into()),
+                    enclosures: vec![],
+                    categories: vec![],
+                },
+                Item {
+                    id: Some("2".into()),
+                    content: Some("a &lt; b".into()),
+                    author: Some("json.item[1].author".into()),
+                    date: Some("2010-02-08T14:04:00-05:00".into()),
+                    ..Item::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn enclosures() {
+        let s = r#"<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/"><channel>
+            <item>
+                <enclosure url="https://example.com/a.mp3" length="1024" type="audio/mpeg"/>
+                <media:content url="https://example.com/a.mp3" type="audio/mpeg"/>
+                <media:thumbnail url="https://example.com/a.jpg"/>
+            </item>
+            <item>
+                <media:group>
+                    <media:title>title</media:title>
+                    <media:thumbnail url="/b.jpg"></media:thumbnail>
+                </media:group>
+                <title>b</title>
+            </item>
+        </channel></rss>"#;
+        let r = fix_relative_url(parse(Cursor::new(s)).unwrap(), "https://example.com/feed");
+        let enclosures: Vec<_> = r.items.iter().map(|item| &item.enclosures).collect();
+        assert_eq!(
+            enclosures,
+            vec![
+                &vec![Enclosure {
+                    url: "https://example.com/a.mp3".into(),
+                    mime_type: Some("audio/mpeg".into()),
+                    length: Some(1024),
+                }],
+                &vec![Enclosure {
+                    url: "https://example.com/b.jpg".into(),
+                    mime_type: Some("image/*".into()),
+                    length: None,
+                }],
+            ]
+        );
+        assert_eq!(r.items[1].title.as_deref(), Some("b"));
+
+        let s = r#"<feed xmlns="http://www.w3.org/2005/Atom"><entry>
+            <link href="https://example.com/1"/>
+            <link rel="enclosure" href="https://example.com/1.mp4" type="video/mp4" length="42"/>
+        </entry></feed>"#;
+        let r = parse(Cursor::new(s)).unwrap();
+        assert_eq!(r.items[0].link.as_deref(), Some("https://example.com/1"));
+        assert_eq!(
+            r.items[0].enclosures[0].mime_type.as_deref(),
+            Some("video/mp4")
+        );
+
+        let s = r#"{"title": "t", "items": [{"attachments": [
+            {"url": "https://example.com/1.pdf", "mime_type": "application/pdf", "size_in_bytes": 7}
+        ]}]}"#;
+        let r: Rss = serde_json::from_str(s).unwrap();
+        assert_eq!(r.items[0].enclosures[0].length, Some(7));
+    }
+
+    #[test]
+    fn categories() {
+        let s = r#"<feed xmlns="http://www.w3.org/2005/Atom"><entry>
+            <category term="rust" label="Rust"/>
+            <category term="release"></category>
+        </entry></feed>"#;
+        let r = parse(Cursor::new(s)).unwrap();
+        assert_eq!(r.items[0].categories, vec!["rust", "release"]);
+
+        let s = r#"{"title": "t", "items": [{"tags": ["a", "b"]}]}"#;
+        let r: Rss = serde_json::from_str(s).unwrap();
+        assert_eq!(r.items[0].categories, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn item_date() {
+        let mut item = Item {
+            date: Some(" Mon, 01 Jan 2001 00:00:00 GMT ".into()),
+            ..Item::default()
+        };
+        assert_eq!(
+            item.parsed_date().map(|d| d.to_rfc3339()),
+            Some("2001-01-01T00:00:00+00:00".into())
+        );
+        item.date = Some("2000-01-01T01:00:00Z".into());
+        assert_eq!(
+            item.parsed_date().map(|d| d.to_rfc3339()),
+            Some("2000-01-01T01:00:00+00:00".into())
+        );
+        item.date = Some("yesterday".into());
+        assert_eq!(item.parsed_date(), None);
+    }
+
+    #[test]
+    fn discover() {
+        let html = r#"<!DOCTYPE html>
+<html><head>
+<link rel="stylesheet" href="/style.css">
+<LINK REL="alternate" TYPE="application/rss+xml" TITLE="RSS" HREF="/feed.xml">
+<link type='application/atom+xml' rel='alternate home' href='atom.xml?a=1&amp;b=2' />
+<link rel=alternate type="application/feed+json; charset=utf-8" href=https://example.com/feed.json>
+<link rel="alternate" type="text/html" hreflang="en" href="/en/">
+<link rel="alternate" type="application/rss+xml" href="/feed.xml">
+</head></html>"#;
+        assert_eq!(
+            discover_feeds(html),
+            vec![
+                "/feed.xml",
+                "atom.xml?a=1&b=2",
+                "https://example.com/feed.json"
+            ]
+        );
+        assert!(discover_feeds("<html></html>").is_empty());
+    }
 }
@@ -4,9 +4,11 @@ use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
+use std::time::SystemTime;
 
 use futures::{future::FutureExt, select_biased};
-use tbot::{types::parameters, Bot};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::{StatusCode, Url};
 use tokio::{
     self,
     sync::{Mutex, Notify},
@@ -15,11 +17,37 @@ use tokio::{
 use tokio_stream::StreamExt;
 use tokio_util::time::DelayQueue;
 
-use crate::client::pull_feed;
-use crate::data::{Database, Feed, FeedUpdate};
-use crate::messages::{format_large_msg, Escape};
+use crate::client::{pull_feed_if_modified, FeedError, Pulled};
+use crate::data::{feed_id, item_key, Chat, Database, DisplayMode, Feed, FeedUpdate, SentMessage};
+use crate::feed::Item;
+use crate::i18n::{self, Lang};
+use crate::messages::{
+    format_large_msg_indexed, sanitize_html, truncate, Escape, TELEGRAM_MAX_MSG_LEN,
+};
+use crate::outbox::{ItemRef, Media, MediaKind, Outbox};
+use crate::template::Template;
+use crate::timezone::{format_item_date, Timezone};
+
+/// Visible length limit of summaries in detailed mode
+const SUMMARY_MAX_CHARS: usize = 1024;
+/// Visible length limit of the titles and the author line in detailed mode
+const HEADER_MAX_CHARS: usize = 256;
+const CAPTION_TITLE_MAX_CHARS: usize = 256;
+/// Don't let a bogus `Retry-After` silence a feed forever
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
 
-pub fn start(bot: Bot, db: Arc<Mutex<Database>>, min_interval: u32, max_interval: u32) {
+/// `--min-interval` and `--max-interval`, set by `start`
+static INTERVALS: OnceCell<(u32, u32)> = OnceCell::new();
+/// When the queued feeds will be fetched
+static SCHEDULE: Lazy<std::sync::Mutex<HashMap<String, SystemTime>>> = Lazy::new(Default::default);
+
+pub fn start(
+    db: Arc<Mutex<Database>>,
+    outbox: Arc<Mutex<Outbox>>,
+    min_interval: u32,
+    max_interval: u32,
+) {
+    INTERVALS.set((min_interval, max_interval)).unwrap();
     let mut queue = FetchQueue::new();
     // TODO: Don't use interval, it can accumulate ticks
     // replace it with delay_until
@@ -30,27 +58,26 @@ pub fn start(bot: Bot, db: Arc<Mutex<Database>>, min_interval: u32, max_interval
             select_biased! {
                 feed = queue.next().fuse() => {
                     let feed = feed.expect("unreachable");
-                    let bot = bot.clone();
                     let db = db.clone();
+                    let outbox = outbox.clone();
                     let opportunity = throttle.acquire();
                     tokio::spawn(async move {
                         opportunity.wait().await;
-                        if let Err(e) = fetch_and_push_updates(bot, db, feed).await {
-                            crate::print_error(e);
-                        }
+                        fetch_and_push_updates(db, outbox, feed).await;
                     });
                 }
                 _ = interval.tick().fuse() => {
                     let feeds = db.lock().await.all_feeds();
                     for feed in feeds {
-                        let feed_interval = cmp::min(
-                            cmp::max(
-                                feed.ttl.map(|ttl| ttl * 60).unwrap_or_default(),
-                                min_interval,
-                            ),
-                            max_interval,
-                        ) as u64 - 1; // after -1, we can stagger with `interval`
-                        queue.enqueue(feed, Duration::from_secs(feed_interval));
+                        // after -1, we can stagger with `interval`
+                        let feed_interval =
+                            fetch_interval(&feed).saturating_sub(Duration::from_secs(1));
+                        let postponed = feed
+                            .retry_after
+                            .and_then(|t| t.duration_since(SystemTime::now()).ok())
+                            .unwrap_or_default();
+                        let delay = cmp::max(feed_interval, postponed);
+                        queue.enqueue(feed, delay);
                     }
                 }
             }
@@ -58,138 +85,400 @@ pub fn start(bot: Bot, db: Arc<Mutex<Database>>, min_interval: u32, max_interval
     });
 }
 
-async fn fetch_and_push_updates(
-    bot: Bot,
-    db: Arc<Mutex<Database>>,
-    feed: Feed,
-) -> Result<(), tbot::errors::MethodCall> {
-    let new_feed = match pull_feed(&feed.link).await {
-        Ok(feed) => feed,
+/// The interval between fetches of the feed, longer if it keeps failing
+pub fn fetch_interval(feed: &Feed) -> Duration {
+    let (min_interval, max_interval) = *INTERVALS.get().expect("fetcher not started");
+    let interval = cmp::min(
+        cmp::max(
+            feed.ttl.map(|ttl| ttl * 60).unwrap_or_default(),
+            backoff_interval(min_interval, feed.error_count),
+        ),
+        max_interval,
+    );
+    Duration::from_secs(interval as u64)
+}
+
+/// When the feed will be fetched next, `None` if it's being fetched or not queued yet
+pub fn next_fetch(rss_link: &str) -> Option<SystemTime> {
+    SCHEDULE.lock().unwrap().get(rss_link).copied()
+}
+
+/// Double the interval on every consecutive failure
+fn backoff_interval(min_interval: u32, error_count: u32) -> u32 {
+    min_interval.saturating_mul(1 << cmp::min(error_count, 16))
+}
+
+async fn fetch_and_push_updates(db: Arc<Mutex<Database>>, outbox: Arc<Mutex<Outbox>>, feed: Feed) {
+    // Another feed on the same host may have been rate limited after this one was queued
+    if db.lock().await.is_postponed(&feed.link) {
+        return;
+    }
+    let pulled = pull_feed_if_modified(
+        &feed.link,
+        feed.etag.as_deref(),
+        feed.last_modified.as_deref(),
+    )
+    .await;
+    let Pulled {
+        feed: new_feed,
+        etag,
+        last_modified,
+    } = match pulled {
+        Ok(Some(pulled)) => pulled,
+        Ok(None) => {
+            db.lock().await.reset_fetch_errors(&feed.link);
+            return;
+        }
+        Err(FeedError::RateLimited {
+            status,
+            retry_after: Some(delay),
+        }) => {
+            let until = SystemTime::now() + cmp::min(delay, MAX_RETRY_AFTER);
+            let mut db = db.lock().await;
+            // 429 is usually counted per client, so back off from the whole host,
+            // while 503 may only be a problem of this feed
+            let host = Url::parse(&feed.link)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from));
+            let links: Vec<String> = match host {
+                Some(host) if status == StatusCode::TOO_MANY_REQUESTS => db
+                    .all_feeds()
+                    .into_iter()
+                    .map(|feed| feed.link)
+                    .filter(|link| {
+                        Url::parse(link)
+                            .map(|url| url.host_str() == Some(host.as_str()))
+                            .unwrap_or(false)
+                    })
+                    .collect(),
+                _ => vec![feed.link.clone()],
+            };
+            db.postpone(links.iter().map(String::as_str), until);
+            return;
+        }
         Err(e) => {
-            let down_time = db.lock().await.get_or_update_down_time(&feed.link);
+            let down_time = {
+                let mut db = db.lock().await;
+                db.record_fetch_error(&feed.link, e.to_user_friendly());
+                db.get_or_update_down_time(&feed.link)
+            };
             if down_time.is_none() {
                 // user unsubscribed while fetching the feed
-                return Ok(());
+                return;
             }
             // 5 days
             if down_time.unwrap().as_secs() > 5 * 24 * 60 * 60 {
-                db.lock().await.reset_down_time(&feed.link);
-                let msg = tr!(
-                    "continuous_fetch_error",
-                    link = Escape(&feed.link),
-                    title = Escape(&feed.title),
-                    error = Escape(&e.to_user_friendly())
-                );
-                push_updates(
-                    &bot,
-                    &db,
-                    feed.subscribers,
-                    parameters::Text::with_html(&msg),
-                )
-                .await?;
+                let mut db = db.lock().await;
+                let mut outbox = outbox.lock().await;
+                db.reset_down_time(&feed.link);
+                push_localized(&db, &mut outbox, feed.active_subscribers(), || {
+                    tr!(
+                        "continuous_fetch_error",
+                        link = Escape(&feed.link),
+                        title = Escape(&feed.title),
+                        error = Escape(&e.to_user_friendly())
+                    )
+                });
+                outbox.flush_or_log();
             }
-            return Ok(());
+            return;
         }
     };
 
-    let updates = db.lock().await.update(&feed.link, new_feed);
+    // The new items are saved as seen only after their messages are in the outbox,
+    // so they are sent again rather than lost if the bot crashes in between
+    let mut db = db.lock().await;
+    let mut outbox = outbox.lock().await;
+    db.defer_saves();
+    let updates = db.update(&feed.link, new_feed, etag, last_modified);
     for update in updates {
         match update {
             FeedUpdate::Items(items) => {
-                let msgs =
-                    format_large_msg(format!("<b>{}</b>", Escape(&feed.title)), &items, |item| {
-                        let title = item
-                            .title
-                            .as_ref()
-                            .map(|s| s.as_str())
-                            .unwrap_or_else(|| &feed.title);
-                        let link = item
-                            .link
-                            .as_ref()
-                            .map(|s| s.as_str())
-                            .unwrap_or_else(|| &feed.link);
-                        format!("<a href=\"{}\">{}</a>", Escape(link), Escape(title))
-                    });
-                for msg in msgs {
-                    push_updates(
-                        &bot,
-                        &db,
-                        feed.subscribers.iter().copied(),
-                        parameters::Text::with_html(&msg),
-                    )
-                    .await?;
+                // Paused subscribers are skipped, but the items are still marked as seen
+                let mut unfiltered = Vec::new();
+                let mut filtered = Vec::new();
+                for subscriber in feed.active_subscribers() {
+                    // The filter of the subscription, or else the default of the chat
+                    let filter = match feed.filters.get(&subscriber) {
+                        Some(filter) => Some(filter.clone()),
+                        None => db.chat(subscriber).filter,
+                    };
+                    match filter {
+                        Some(filter) => filtered.push((subscriber, filter)),
+                        None => unfiltered.push(subscriber),
+                    }
+                }
+                push_items(&mut db, &mut outbox, &feed, unfiltered, &items);
+                for (subscriber, filter) in filtered {
+                    let items: Vec<Item> = items
+                        .iter()
+                        .filter(|item| filter.matches(item))
+                        .cloned()
+                        .collect();
+                    push_items(&mut db, &mut outbox, &feed, Some(subscriber), &items);
                 }
             }
+            FeedUpdate::Edited(items) => push_edits(&db, &mut outbox, &feed, &items),
             FeedUpdate::Title(new_title) => {
-                let msg = tr!(
-                    "feed_renamed",
-                    link = Escape(&feed.link),
-                    title = Escape(&feed.title),
-                    new_title = Escape(&new_title)
-                );
-                push_updates(
-                    &bot,
-                    &db,
-                    feed.subscribers.iter().copied(),
-                    parameters::Text::with_html(&msg),
-                )
-                .await?;
+                push_localized(&db, &mut outbox, feed.active_subscribers(), || {
+                    tr!(
+                        "feed_renamed",
+                        link = Escape(&feed.link),
+                        title = Escape(&feed.title),
+                        new_title = Escape(&new_title)
+                    )
+                });
             }
         }
     }
-    Ok(())
+    outbox.flush_or_log();
+    db.save_deferred();
 }
 
-async fn push_updates<I: IntoIterator<Item = i64>>(
-    bot: &Bot,
-    db: &Arc<Mutex<Database>>,
+/// Format the message in the language of each subscriber
+fn push_localized<I, F>(db: &Database, outbox: &mut Outbox, subscribers: I, format: F)
+where
+    I: IntoIterator<Item = i64>,
+    F: Fn() -> String,
+{
+    let mut groups: Vec<(Lang, Vec<i64>)> = Vec::new();
+    for subscriber in subscribers {
+        let lang = db.chat(subscriber).lang();
+        match groups.iter_mut().find(|(l, _)| *l == lang) {
+            Some((_, group)) => group.push(subscriber),
+            None => groups.push((lang, vec![subscriber])),
+        }
+    }
+    for (lang, subscribers) in groups {
+        let msg = i18n::with(lang, &format);
+        outbox.push(subscribers, &msg);
+    }
+}
+
+/// How items are formatted for a subscriber
+#[derive(Clone, PartialEq)]
+struct Layout {
+    template: Option<Template>,
+    /// One message per item, or a list in one message
+    detailed: bool,
+    /// Items with enclosures are sent as media
+    media: bool,
+    /// Timezone of dates
+    timezone: Option<Timezone>,
+    /// Preview the link of the first item in each message
+    link_preview: bool,
+    /// Record the messages of items to edit them, only in detailed mode
+    edit_messages: bool,
+}
+
+impl Layout {
+    fn new(feed: &Feed, subscriber: i64, chat: &Chat) -> Layout {
+        let detailed = chat.display_mode == DisplayMode::Detailed;
+        Layout {
+            template: feed
+                .templates
+                .get(&subscriber)
+                .or(chat.template.as_ref())
+                .cloned(),
+            detailed,
+            media: feed.media.contains(&subscriber),
+            timezone: chat.timezone,
+            link_preview: feed
+                .link_previews
+                .get(&subscriber)
+                .copied()
+                .unwrap_or(chat.link_preview),
+            edit_messages: detailed && chat.edit_messages,
+        }
+    }
+
+    /// The message of an item in detailed mode
+    fn render_detailed(&self, feed: &Feed, item: &Item) -> String {
+        match &self.template {
+            Some(template) => template.render(feed, item, self.timezone),
+            None => format_detailed_item(feed, item, self.timezone),
+        }
+    }
+}
+
+fn push_items<I: IntoIterator<Item = i64>>(
+    db: &mut Database,
+    outbox: &mut Outbox,
+    feed: &Feed,
     subscribers: I,
-    msg: parameters::Text,
-) -> Result<(), tbot::errors::MethodCall> {
-    use tbot::errors::MethodCall;
-    for mut subscriber in subscribers {
-        'retry: for _ in 0..3 {
-            match bot
-                .send_message(tbot::types::chat::Id(subscriber), msg.clone())
-                .is_web_page_preview_disabled(true)
-                .call()
-                .await
-            {
-                Err(MethodCall::RequestError { description, .. })
-                    if chat_is_unavailable(&description) =>
-                {
-                    db.lock().await.delete_subscriber(subscriber);
-                }
-                Err(MethodCall::RequestError {
-                    migrate_to_chat_id: Some(new_chat_id),
-                    ..
-                }) => {
-                    db.lock().await.update_subscriber(subscriber, new_chat_id.0);
-                    subscriber = new_chat_id.0;
-                    continue 'retry;
-                }
-                Err(MethodCall::RequestError {
-                    retry_after: Some(delay),
-                    ..
-                }) => {
-                    time::sleep(Duration::from_secs(delay)).await;
-                    continue 'retry;
-                }
-                other => {
-                    other?;
-                }
+    items: &[Item],
+) {
+    if items.is_empty() {
+        return;
+    }
+    let mut groups: Vec<(Layout, Vec<i64>)> = Vec::new();
+    for subscriber in subscribers {
+        let chat = db.chat(subscriber);
+        if chat.digest.is_some() {
+            db.add_digest_items(subscriber, feed, items);
+            continue;
+        }
+        let layout = Layout::new(feed, subscriber, &chat);
+        match groups.iter_mut().find(|(l, _)| *l == layout) {
+            Some((_, group)) => group.push(subscriber),
+            None => groups.push((layout, vec![subscriber])),
+        }
+    }
+    for (layout, subscribers) in groups {
+        if !layout.media {
+            push_text_items(outbox, feed, &layout, &subscribers, items);
+            continue;
+        }
+        // Items with enclosures are sent as media, the others as usual
+        let (with_media, without_media): (Vec<Item>, Vec<Item>) = items
+            .iter()
+            .cloned()
+            .partition(|item| !item.enclosures.is_empty());
+        for item in &with_media {
+            let caption = format_caption(feed, item);
+            for enclosure in &item.enclosures {
+                let media = Media {
+                    kind: MediaKind::from_mime_type(enclosure.mime_type.as_deref()),
+                    url: enclosure.url.clone(),
+                    length: enclosure.length,
+                };
+                outbox.push_media(subscribers.iter().copied(), media, &caption);
+            }
+        }
+        push_text_items(outbox, feed, &layout, &subscribers, &without_media);
+    }
+}
+
+fn push_text_items(
+    outbox: &mut Outbox,
+    feed: &Feed,
+    layout: &Layout,
+    subscribers: &[i64],
+    items: &[Item],
+) {
+    if items.is_empty() {
+        return;
+    }
+    // With the index of the first item
+    let msgs = match (&layout.template, layout.detailed) {
+        (None, false) => {
+            format_large_msg_indexed(format!("<b>{}</b>", Escape(&feed.title)), items, |item| {
+                let title = item
+                    .title
+                    .as_ref()
+                    .map(|s| s.as_str())
+                    .unwrap_or_else(|| &feed.title);
+                let link = item
+                    .link
+                    .as_ref()
+                    .map(|s| s.as_str())
+                    .unwrap_or_else(|| &feed.link);
+                format!("<a href=\"{}\">{}</a>", Escape(link), Escape(title))
+            })
+        }
+        (Some(template), false) => format_large_msg_indexed(String::new(), items, |item| {
+            template.render(feed, item, layout.timezone)
+        }),
+        (_, true) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (layout.render_detailed(feed, item), Some(i)))
+            .collect(),
+    };
+    for (msg, first) in msgs.iter().filter(|(msg, _)| !msg.is_empty()) {
+        let link_preview = first
+            .filter(|_| layout.link_preview)
+            .and_then(|i| items[i].link.as_deref());
+        // Messages with a single item in detailed mode
+        let item = first
+            .filter(|_| layout.edit_messages)
+            .and_then(|i| item_key(&items[i]))
+            .map(|key| ItemRef {
+                feed: feed_id(&feed.link),
+                item: key,
+            });
+        outbox.push_item(subscribers.iter().copied(), msg, link_preview, item);
+    }
+}
+
+/// Edit the sent messages of changed items, in the current layout of each chat,
+/// chats that paused the feed are already left out by `Database::update`
+fn push_edits(db: &Database, outbox: &mut Outbox, feed: &Feed, items: &[(Item, Vec<SentMessage>)]) {
+    for (item, messages) in items {
+        for message in messages {
+            let layout = Layout::new(feed, message.chat, &db.chat(message.chat));
+            if !layout.edit_messages {
+                continue;
+            }
+            let msg = layout.render_detailed(feed, item);
+            let link_preview = item.link.as_deref().filter(|_| layout.link_preview);
+            if !msg.is_empty() {
+                outbox.push_edit(message.chat, message.message_id, &msg, link_preview);
             }
-            break 'retry;
         }
     }
-    Ok(())
 }
 
-pub fn chat_is_unavailable(s: &str) -> bool {
-    s.contains("Forbidden")
-        || s.contains("chat not found")
-        || s.contains("have no rights")
-        || s.contains("need administrator rights")
+/// Captions are limited to 1024 chars, only the titles are included
+fn format_caption(feed: &Feed, item: &Item) -> String {
+    let title = item.title.as_deref().unwrap_or(&feed.title);
+    let title: String = title.chars().take(CAPTION_TITLE_MAX_CHARS).collect();
+    let link = item.link.as_deref().unwrap_or(&feed.link);
+    format!(
+        "<b>{}</b>\n<a href=\"{}\">{}</a>",
+        Escape(&feed.title),
+        Escape(link),
+        Escape(&title)
+    )
+}
+
+fn format_detailed_item(feed: &Feed, item: &Item, timezone: Option<Timezone>) -> String {
+    let feed_title = truncate(&feed.title, HEADER_MAX_CHARS);
+    let title = truncate(
+        item.title.as_deref().unwrap_or(&feed.title),
+        HEADER_MAX_CHARS,
+    );
+    let link = item.link.as_deref().unwrap_or(&feed.link);
+    let mut msg = format!(
+        "<b>{}</b>\n<a href=\"{}\">{}</a>",
+        Escape(&feed_title),
+        Escape(link),
+        Escape(&title)
+    );
+    // Telegram counts the visible text, including the blank line before the summary
+    let mut visible = feed_title.chars().count() + 1 + title.chars().count() + 2;
+
+    let date = format_item_date(item, timezone);
+    let meta: Vec<&str> = item
+        .author
+        .iter()
+        .chain(date.iter())
+        .map(|s| s.trim())
+        .collect();
+    if !meta.is_empty() {
+        let meta = meta.join(" · ");
+        let meta = truncate(&meta, HEADER_MAX_CHARS);
+        visible += 1 + meta.chars().count();
+        msg.push_str(&format!("\n<i>{}</i>", Escape(&meta)));
+    }
+
+    // Messages are limited to 4096 chars, one is left for the ellipsis of the summary
+    // and one for the link preview
+    let max_chars = cmp::min(
+        SUMMARY_MAX_CHARS,
+        TELEGRAM_MAX_MSG_LEN.saturating_sub(visible + 2),
+    );
+    let summary = item
+        .summary
+        .as_ref()
+        .or_else(|| item.content.as_ref())
+        .map(|summary| sanitize_html(summary, max_chars))
+        .filter(|summary| !summary.is_empty());
+    if let Some(summary) = summary {
+        msg.push_str("\n\n");
+        msg.push_str(&summary);
+    }
+    msg
 }
 
 #[derive(Default)]
@@ -207,6 +496,10 @@ impl FetchQueue {
     fn enqueue(&mut self, feed: Feed, delay: Duration) -> bool {
         let exists = self.feeds.contains_key(&feed.link);
         if !exists {
+            SCHEDULE
+                .lock()
+                .unwrap()
+                .insert(feed.link.clone(), SystemTime::now() + delay);
             self.notifies.insert(feed.link.clone(), delay);
             self.feeds.insert(feed.link.clone(), feed);
             self.wakeup.notify_waiters();
@@ -218,6 +511,7 @@ impl FetchQueue {
         loop {
             if let Some(feed_id) = self.notifies.next().await {
                 let feed = self.feeds.remove(feed_id.get_ref()).unwrap();
+                SCHEDULE.lock().unwrap().remove(&feed.link);
                 break Ok(feed);
             } else {
                 self.wakeup.notified().await;
@@ -0,0 +1,309 @@
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use crate::feed::Item;
+use crate::messages::html_to_text;
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("rule `{0}` must start with `+`, `-` or `/`")]
+    MissingPrefix(String),
+    #[error("rule `{0}` is empty")]
+    Empty(String),
+    #[error("rule `{0}` must end with `/`, write `\\/` for a `/` in the regex")]
+    RegexEnd(String),
+    #[error("invalid regex `{0}`: {1}")]
+    Regex(String, #[source] regex::Error),
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Keyword(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn is_match(&self, lowercase_text: &str, text: &str) -> bool {
+        match self {
+            Pattern::Keyword(keyword) => lowercase_text.contains(keyword.as_str()),
+            Pattern::Regex(re) => re.is_match(text),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Keyword(keyword) => f.write_str(keyword),
+            Pattern::Regex(re) => write!(f, "/{}/", re.as_str().replace('/', "\\/")),
+        }
+    }
+}
+
+/// Split the first rule off `s`, which starts with it, and return the rest
+///
+/// A regex is read up to the closing `/`, so it can contain spaces, `\/` is a `/` in it
+fn next_rule(s: &str) -> Result<(bool, Pattern, &str), FilterError> {
+    let word_end = s.find(char::is_whitespace).unwrap_or(s.len());
+    let (include, sign_len) = match s.as_bytes()[0] {
+        b'+' => (true, 1),
+        b'-' => (false, 1),
+        b'/' => (true, 0),
+        _ => return Err(FilterError::MissingPrefix(s[..word_end].to_owned())),
+    };
+    if !s[sign_len..].starts_with('/') {
+        let keyword = &s[sign_len..word_end];
+        if keyword.is_empty() {
+            return Err(FilterError::Empty(s[..word_end].to_owned()));
+        }
+        let pattern = Pattern::Keyword(keyword.to_lowercase());
+        return Ok((include, pattern, &s[word_end..]));
+    }
+    let mut re = String::new();
+    let mut chars = s.char_indices().skip(sign_len + 1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, '/')) => re.push('/'),
+                Some((_, c)) => {
+                    re.push('\\');
+                    re.push(c);
+                }
+                None => break,
+            },
+            '/' => {
+                let end = i + 1;
+                let rest = &s[end..];
+                if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                    let rule_end = end + rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    return Err(FilterError::RegexEnd(s[..rule_end].to_owned()));
+                }
+                if re.is_empty() {
+                    return Err(FilterError::Empty(s[..end].to_owned()));
+                }
+                let pattern = Regex::new(&re)
+                    .map(Pattern::Regex)
+                    .map_err(|e| FilterError::Regex(re, e))?;
+                return Ok((include, pattern, rest));
+            }
+            c => re.push(c),
+        }
+    }
+    Err(FilterError::RegexEnd(s.to_owned()))
+}
+
+/// Include and exclude rules of a subscription
+///
+/// Syntax: `+keyword -keyword /regex/ -/regex/`, keywords are case-insensitive,
+/// a regex can contain spaces and `\/` for a `/`.
+/// An item is accepted if it matches any include rule (or there is none)
+/// and matches no exclude rule.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(into = "String")]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// A stored filter, rules that no longer parse (f.e. after the syntax changed)
+    /// are logged and dropped, instead of failing to load the database
+    pub fn from_stored(s: &str) -> Filter {
+        let mut filter = Filter::default();
+        let mut rest = s.trim_start();
+        while !rest.is_empty() {
+            match next_rule(rest) {
+                Ok((include, pattern, after)) => {
+                    filter.push(include, pattern);
+                    rest = after;
+                }
+                Err(e) => {
+                    eprintln!("Dropped a rule of the stored filter `{}`: {}", s, e);
+                    rest = &rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..];
+                }
+            }
+            rest = rest.trim_start();
+        }
+        filter
+    }
+
+    fn push(&mut self, include: bool, pattern: Pattern) {
+        if include {
+            self.include.push(pattern);
+        } else {
+            self.exclude.push(pattern);
+        }
+    }
+
+    /// Title, summary and content of the item are matched, only the text of the HTML
+    pub fn matches(&self, item: &Item) -> bool {
+        let html = [&item.summary, &item.content]
+            .into_iter()
+            .filter_map(|html| html.as_deref())
+            .map(html_to_text);
+        let texts: Vec<(String, String)> = item
+            .title
+            .iter()
+            .cloned()
+            .chain(html)
+            .map(|text| (text.to_lowercase(), text))
+            .collect();
+        let is_match = |pattern: &Pattern| {
+            texts
+                .iter()
+                .any(|(lowercase_text, text)| pattern.is_match(lowercase_text, text))
+        };
+        (self.include.is_empty() || self.include.iter().any(is_match))
+            && !self.exclude.iter().any(is_match)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let include = self.include.iter().map(|p| ('+', p));
+        let exclude = self.exclude.iter().map(|p| ('-', p));
+        for (i, (sign, pattern)) in include.chain(exclude).enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}{}", sign, pattern)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::default();
+        let mut rest = s.trim_start();
+        while !rest.is_empty() {
+            let (include, pattern, after) = next_rule(rest)?;
+            filter.push(include, pattern);
+            rest = after.trim_start();
+        }
+        Ok(filter)
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl Eq for Filter {}
+
+impl From<Filter> for String {
+    fn from(filter: Filter) -> String {
+        filter.to_string()
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|s| Filter::from_stored(&s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn item(title: &str) -> Item {
+        Item {
+            title: Some(title.into()),
+            ..Item::default()
+        }
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let filter: Filter = "+Rust -beta /v\\d+/ -/rc\\d/".parse().unwrap();
+        assert_eq!(filter.to_string(), "+rust +/v\\d+/ -beta -/rc\\d/");
+
+        // A regex is one rule up to the closing `/`
+        let filter: Filter = r"+/release notes/  -/a\/b/ +x".parse().unwrap();
+        assert_eq!(filter.to_string(), r"+/release notes/ +x -/a\/b/");
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+        assert!(filter.matches(&item("release notes of a/c")));
+        assert!(!filter.matches(&item("x a/b")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            "rust".parse::<Filter>(),
+            Err(FilterError::MissingPrefix(_))
+        ));
+        assert!(matches!("+".parse::<Filter>(), Err(FilterError::Empty(_))));
+        assert!(matches!(
+            "+/(/".parse::<Filter>(),
+            Err(FilterError::Regex(..))
+        ));
+        assert!(matches!(
+            "+//".parse::<Filter>(),
+            Err(FilterError::Empty(_))
+        ));
+        assert!(matches!(
+            "+/a b".parse::<Filter>(),
+            Err(FilterError::RegexEnd(rule)) if rule == "+/a b"
+        ));
+        assert!(matches!(
+            "-/a/b +c".parse::<Filter>(),
+            Err(FilterError::RegexEnd(rule)) if rule == "-/a/b"
+        ));
+    }
+
+    #[test]
+    fn matching() {
+        let filter: Filter = "+release +/^v\\d/ -beta".parse().unwrap();
+        assert!(filter.matches(&item("New Release")));
+        assert!(filter.matches(&item("v1.2.0")));
+        assert!(!filter.matches(&item("v1.2.0-beta")));
+        assert!(!filter.matches(&item("Weekly news")));
+
+        let exclude_only: Filter = "-nightly".parse().unwrap();
+        assert!(exclude_only.matches(&item("Stable")));
+        assert!(!exclude_only.matches(&item("Nightly build")));
+        assert!(Filter::default().matches(&item("anything")));
+
+        let with_summary = Item {
+            summary: Some("Contains a Release".into()),
+            ..item("Weekly news")
+        };
+        assert!(filter.matches(&with_summary));
+
+        // Only the text of the HTML
+        let with_html = |html: &str| Item {
+            content: Some(html.into()),
+            ..item("Weekly news")
+        };
+        assert!(!filter.matches(&with_html(r#"<a href="http://release.com">link</a>"#)));
+        assert!(filter.matches(&with_html("<p>New <i>Release</i></p>")));
+        let html_filter: Filter = "+/(?m)^Release$/ -/<b>/".parse().unwrap();
+        assert!(html_filter.matches(&with_html("<p><b>Release</b></p>")));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let filter: Filter = "+a -b".parse().unwrap();
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(json, "\"+a -b\"");
+        let filter: Filter = serde_json::from_str(&json).unwrap();
+        assert_eq!(filter.to_string(), "+a -b");
+
+        // Bad rules are dropped, not the whole filter
+        let filter: Filter = serde_json::from_str(r#""+a /(/ c -/d/e -f""#).unwrap();
+        assert_eq!(filter.to_string(), "+a -f");
+    }
+}
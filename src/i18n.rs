@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+
+// `DEFAULT_LOCALE` and `STRINGS`, generated from `locales/*.toml`
+include!(concat!(env!("OUT_DIR"), "/locales.rs"));
+
+/// Used for keys missing in other locales
+const FALLBACK_LOCALE: &str = "en";
+
+static DEFAULT: OnceCell<Lang> = OnceCell::new();
+
+lazy_static! {
+    static ref LOCALES: Vec<Locale> = STRINGS
+        .iter()
+        .map(|(code, strings)| Locale {
+            code,
+            strings: strings.iter().copied().collect(),
+        })
+        .collect();
+}
+
+tokio::task_local! {
+    static CURRENT: Lang;
+}
+
+struct Locale {
+    code: &'static str,
+    strings: HashMap<&'static str, &'static str>,
+}
+
+/// A locale compiled into the binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lang(usize);
+
+impl Lang {
+    /// Match a locale code or a Telegram `language_code`, f.e. `en`, `en-US` or `zh-hans`
+    pub fn find(code: &str) -> Option<Lang> {
+        let code = code.split(&['-', '_'][..]).next()?;
+        LOCALES
+            .iter()
+            .position(|locale| locale.code.eq_ignore_ascii_case(code))
+            .map(Lang)
+    }
+
+    pub fn all() -> impl Iterator<Item = Lang> {
+        (0..LOCALES.len()).map(Lang)
+    }
+
+    pub fn code(self) -> &'static str {
+        LOCALES[self.0].code
+    }
+
+    /// The name of the language in itself
+    pub fn name(self) -> &'static str {
+        LOCALES[self.0].strings.get("language_name").unwrap_or(&"?")
+    }
+}
+
+impl Display for Lang {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name(), self.code())
+    }
+}
+
+/// Set the language of chats without one, `code` must be a compiled locale
+pub fn init_default(code: &str) {
+    let lang = Lang::find(code).expect("unknown locale");
+    DEFAULT.set(lang).expect("default locale already set");
+}
+
+pub fn default_lang() -> Lang {
+    DEFAULT
+        .get()
+        .copied()
+        .or_else(|| Lang::find(DEFAULT_LOCALE))
+        .unwrap_or(Lang(0))
+}
+
+/// The language of the running task, `tr!` uses it
+pub fn current() -> Lang {
+    CURRENT
+        .try_with(|lang| *lang)
+        .unwrap_or_else(|_| default_lang())
+}
+
+/// Run `future` with `tr!` translating to `lang`
+pub async fn scope<F: Future>(lang: Lang, future: F) -> F::Output {
+    CURRENT.scope(lang, future).await
+}
+
+/// Run `f` with `tr!` translating to `lang`
+pub fn with<R>(lang: Lang, f: impl FnOnce() -> R) -> R {
+    CURRENT.sync_scope(lang, f)
+}
+
+/// Look up the key in the current language, or in English if it's not translated
+pub fn translate(key: &'static str) -> &'static str {
+    let fallback = || {
+        let lang = Lang::find(FALLBACK_LOCALE)?;
+        LOCALES[lang.0].strings.get(key).copied()
+    };
+    LOCALES[current().0]
+        .strings
+        .get(key)
+        .copied()
+        .or_else(fallback)
+        .unwrap_or(key)
+}
+
+/// Replace `{name}` with the argument, `{{` and `}}` are literal braces
+pub fn format(s: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find(&['{', '}'][..]) {
+        output.push_str(&rest[..pos]);
+        let (c, after) = (&rest[pos..pos + 1], &rest[pos + 1..]);
+        if after.starts_with(c) {
+            output.push_str(c);
+            rest = &after[1..];
+            continue;
+        }
+        if c == "}" {
+            output.push('}');
+            rest = after;
+            continue;
+        }
+        let arg = after.find('}').and_then(|end| {
+            let (_, value) = args.iter().find(|(name, _)| *name == &after[..end])?;
+            Some((end, value))
+        });
+        match arg {
+            Some((end, value)) => {
+                output.push_str(&value.to_string());
+                rest = &after[end + 1..];
+            }
+            None => {
+                output.push_str(c);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn placeholders(s: &str) -> Vec<&str> {
+        let mut names: Vec<&str> = s
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c == '_' || c.is_alphanumeric())
+            })
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    #[test]
+    fn locales_match_english() {
+        let en = Lang::find(FALLBACK_LOCALE).unwrap();
+        let en = &LOCALES[en.0].strings;
+        for locale in LOCALES.iter() {
+            assert!(locale.strings.contains_key("language_name"));
+            for (key, value) in &locale.strings {
+                let english = en
+                    .get(key)
+                    .unwrap_or_else(|| panic!("{} is not in English", key));
+                assert_eq!(
+                    placeholders(value),
+                    placeholders(english),
+                    "{}: {}",
+                    locale.code,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn find_lang() {
+        assert_eq!(Lang::find("en-US").map(Lang::code), Some("en"));
+        assert_eq!(Lang::find("zh-hans").map(Lang::code), Some("zh"));
+        assert_eq!(Lang::find("ZH").map(Lang::code), Some("zh"));
+        assert_eq!(Lang::find("xx"), None);
+        assert_eq!(Lang::find(""), None);
+    }
+
+    #[test]
+    fn translate_in_scope() {
+        let en = Lang::find("en").unwrap();
+        let zh = Lang::find("zh").unwrap();
+        assert_eq!(with(en, || translate("language_name")), "English");
+        assert_eq!(with(zh, || translate("language_name")), "中文");
+        assert_eq!(with(zh, || translate("no_such_key")), "no_such_key");
+    }
+
+    #[test]
+    fn format_args() {
+        let args: &[(&str, &dyn Display)] = &[("title", &"a"), ("count", &2)];
+        assert_eq!(format("{title}: {count}", args), "a: 2");
+        assert_eq!(format("{{title}} {unknown} }", args), "{title} {unknown} }");
+    }
+}
@@ -3,10 +3,12 @@
 
 use std::convert::TryInto;
 use std::env;
+use std::net::SocketAddr;
 use std::panic;
 use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use hyper_proxy::{Intercept, Proxy};
@@ -15,20 +17,30 @@ use structopt::StructOpt;
 use tbot;
 use tbot::bot::Uri;
 use tokio::{self, sync::Mutex};
+use url::Url;
 
-// Include the tr! macro and localizations
-include!(concat!(env!("OUT_DIR"), "/ctl10n_macros.rs"));
+// Include the tr! macro, localizations are in `i18n`
+include!(concat!(env!("OUT_DIR"), "/tr_macro.rs"));
 
 mod client;
 mod commands;
 mod data;
+mod digest;
 mod feed;
 mod fetcher;
+mod filter;
 mod gardener;
+mod i18n;
 mod messages;
 mod opml;
+mod outbox;
+mod quiet;
+mod sender;
+mod template;
+mod timezone;
 
-use crate::data::Database;
+use crate::data::{Database, StorageKind};
+use crate::outbox::Outbox;
 
 static BOT_NAME: OnceCell<String> = OnceCell::new();
 static BOT_ID: OnceCell<tbot::types::user::Id> = OnceCell::new();
@@ -49,6 +61,15 @@ pub struct Opt {
         default_value = "./rssbot.json"
     )]
     database: PathBuf,
+    /// Storage of the database, sqlite uses the database path with the extension `.sqlite`
+    /// and imports the JSON database on the first start
+    #[structopt(
+        long,
+        value_name = "json|sqlite",
+        default_value = "json",
+        possible_values = &["json", "sqlite"]
+    )]
+    storage: StorageKind,
     /// Minimum fetch interval
     #[structopt(
         long,
@@ -67,6 +88,17 @@ pub struct Opt {
     )]
     // default is 12 hours
     max_interval: u32,
+    /// Maximum concurrent requests to the same host
+    #[structopt(
+        long,
+        value_name = "number",
+        default_value = "2",
+        validator(check_host_concurrency)
+    )]
+    host_concurrency: usize,
+    /// Minimum spacing between requests to the same host
+    #[structopt(long, value_name = "seconds", default_value = "1")]
+    host_spacing: u64,
     /// Maximum feed size, 0 is unlimited
     #[structopt(long, value_name = "bytes", default_value = "2097152")]
     // default is 2MiB
@@ -80,6 +112,10 @@ pub struct Opt {
         alias = "single_user" // For compatibility
     )]
     admin: Vec<i64>,
+    /// Chat to report failing deliveries to, besides the admins,
+    /// the bot must be able to send messages there
+    #[structopt(long, value_name = "chat id", allow_hyphen_values = true)]
+    log_chat: Option<i64>,
     /// Make bot commands only accessible for group admins.
     #[structopt(long)]
     restricted: bool,
@@ -93,6 +129,23 @@ pub struct Opt {
     /// DANGER: Insecure mode, accept invalid TLS certificates
     #[structopt(long)]
     insecure: bool,
+    /// Receive updates via webhook instead of long polling,
+    /// this URL must be reachable by Telegram and forward requests to
+    /// the address specified by --webhook-listen
+    #[structopt(long, value_name = "url")]
+    webhook_url: Option<Url>,
+    /// Address for the webhook server to listen on
+    #[structopt(long, value_name = "ip:port", default_value = "127.0.0.1:8443")]
+    webhook_listen: SocketAddr,
+    /// Secret appended to the webhook URL path, requests to other paths are rejected
+    #[structopt(long, value_name = "token", validator(check_webhook_secret))]
+    webhook_secret: Option<String>,
+    /// Self-signed certificate (PEM) of the webhook URL, uploaded to Telegram
+    #[structopt(long, value_name = "path")]
+    webhook_cert: Option<PathBuf>,
+    /// Language of chats that haven't chosen one, defaults to the LOCALE of the build
+    #[structopt(long, value_name = "code", validator(check_locale))]
+    locale: Option<String>,
 }
 
 fn check_interval(s: String) -> Result<(), String> {
@@ -105,12 +158,51 @@ fn check_interval(s: String) -> Result<(), String> {
     })
 }
 
+fn check_host_concurrency(s: String) -> Result<(), String> {
+    s.parse::<usize>().map_err(|e| e.to_string()).and_then(|r| {
+        if r < 1 {
+            Err("must >= 1".into())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+fn check_locale(s: String) -> Result<(), String> {
+    match i18n::Lang::find(&s) {
+        Some(_) => Ok(()),
+        None => {
+            let locales: Vec<&str> = i18n::Lang::all().map(i18n::Lang::code).collect();
+            Err(format!("available locales: {}", locales.join(", ")))
+        }
+    }
+}
+
+fn check_webhook_secret(s: String) -> Result<(), String> {
+    if !s.is_empty()
+        && s.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+    {
+        Ok(())
+    } else {
+        Err("only A-Z, a-z, 0-9, _ and - are allowed".into())
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     enable_fail_fast();
 
     let opt = Opt::from_args();
-    let db = Arc::new(Mutex::new(Database::open(opt.database.clone())?));
+    i18n::init_default(opt.locale.as_deref().unwrap_or(i18n::DEFAULT_LOCALE));
+    let db = match opt.storage {
+        StorageKind::Json => Database::open(opt.database.clone())?,
+        StorageKind::Sqlite => Database::open_sqlite(opt.database.clone())?,
+    };
+    let db = Arc::new(Mutex::new(db));
+    let outbox = Arc::new(Mutex::new(Outbox::open(
+        opt.database.with_extension("outbox.jsonl"),
+    )?));
     let bot_builder = tbot::bot::Builder::with_string_token(opt.token.clone())
         .server_uri(opt.api_uri.clone());
     let bot = if let Some(proxy) = init_proxy() {
@@ -125,21 +217,66 @@ async fn main() -> anyhow::Result<()> {
         .context("Initialization failed, check your network and Telegram token")?;
 
     let bot_name = me.user.username.clone().unwrap();
-    crate::client::init_client(&bot_name, opt.insecure, opt.max_feed_size);
+    let host_limiter =
+        client::HostLimiter::new(opt.host_concurrency, Duration::from_secs(opt.host_spacing));
+    crate::client::init_client(&bot_name, opt.insecure, opt.max_feed_size, host_limiter);
 
     BOT_NAME.set(bot_name).unwrap();
     BOT_ID.set(me.user.id).unwrap();
 
     gardener::start_pruning(bot.clone(), db.clone());
-    fetcher::start(bot.clone(), db.clone(), opt.min_interval, opt.max_interval);
+    let report_to = opt.admin.iter().copied().chain(opt.log_chat).collect();
+    sender::start(bot.clone(), db.clone(), outbox.clone(), report_to);
+    digest::start(db.clone(), outbox.clone());
+    fetcher::start(db.clone(), outbox, opt.min_interval, opt.max_interval);
 
     let opt = Arc::new(opt);
 
     let mut event_loop = bot.event_loop();
     event_loop.username(me.user.username.unwrap());
-    commands::register_commands(&mut event_loop, opt, db);
+    commands::register_commands(&mut event_loop, opt.clone(), db);
 
-    event_loop.polling().start().await.unwrap();
+    if let Some(webhook_url) = &opt.webhook_url {
+        start_webhook(event_loop, &opt, webhook_url).await?;
+    } else {
+        event_loop.polling().start().await.unwrap();
+    }
+    Ok(())
+}
+
+async fn start_webhook(
+    event_loop: tbot::EventLoop,
+    opt: &Opt,
+    webhook_url: &Url,
+) -> anyhow::Result<()> {
+    let certificate = opt
+        .webhook_cert
+        .as_ref()
+        .map(std::fs::read_to_string)
+        .transpose()
+        .context("Failed to read the webhook certificate")?;
+    // The secret goes before the query and fragment
+    let mut url = webhook_url.clone();
+    if let Some(secret) = &opt.webhook_secret {
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("The webhook URL must be an http(s) URL"))?
+            .pop_if_empty()
+            .push(secret);
+    }
+    let path = url.path().to_owned();
+
+    let mut webhook = event_loop
+        .webhook(url.as_str(), opt.webhook_listen.port())
+        .bind_to(opt.webhook_listen.ip())
+        .accept_updates_on(path);
+    if let Some(certificate) = &certificate {
+        webhook = webhook.certificate(certificate);
+    }
+    webhook
+        .http()
+        .start()
+        .await
+        .context("Failed to start the webhook server")?;
     Ok(())
 }
 
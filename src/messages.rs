@@ -1,25 +1,59 @@
+use std::borrow::Cow;
+use std::cmp;
 use std::fmt;
 
+use lazy_static::lazy_static;
+use regex::Regex;
+
 pub const TELEGRAM_MAX_MSG_LEN: usize = 4096;
 
 pub fn format_large_msg<T, F>(head: String, data: &[T], line_format_fn: F) -> Vec<String>
 where
     F: Fn(&T) -> String,
 {
-    let mut msgs = vec![head];
-    for item in data {
+    format_large_msg_indexed(head, data, line_format_fn)
+        .into_iter()
+        .map(|(msg, _)| msg)
+        .collect()
+}
+
+/// The same as `format_large_msg`, with the index of the first item in each message,
+/// `None` if the message has only the head
+pub fn format_large_msg_indexed<T, F>(
+    head: String,
+    data: &[T],
+    line_format_fn: F,
+) -> Vec<(String, Option<usize>)>
+where
+    F: Fn(&T) -> String,
+{
+    let mut msgs = vec![(head, None)];
+    for (i, item) in data.iter().enumerate() {
         let line = line_format_fn(item);
-        if msgs.last_mut().unwrap().len() + line.len() > TELEGRAM_MAX_MSG_LEN {
-            msgs.push(line);
+        let (msg, first) = msgs.last_mut().unwrap();
+        if msg.len() + line.len() > TELEGRAM_MAX_MSG_LEN {
+            msgs.push((line, Some(i)));
+        } else if msg.is_empty() {
+            // Without a head
+            *msg = line;
+            *first = Some(i);
         } else {
-            let msg = msgs.last_mut().unwrap();
             msg.push('\n');
             msg.push_str(&line);
+            first.get_or_insert(i);
         }
     }
     msgs
 }
 
+/// Cut `s` to `max_chars` characters, an ellipsis is appended if it was cut
+pub fn truncate(s: &str, max_chars: usize) -> Cow<'_, str> {
+    match s.char_indices().nth(max_chars) {
+        Some((end, _)) => Cow::Owned(format!("{}…", &s[..end])),
+        None => Cow::Borrowed(s),
+    }
+}
+
 pub struct Escape<'a>(pub &'a str);
 
 impl<'a> fmt::Display for Escape<'a> {
@@ -52,3 +86,335 @@ impl<'a> fmt::Display for Escape<'a> {
         Ok(())
     }
 }
+
+/// Tags supported by Telegram, with their aliases
+const ALLOWED_TAGS: &[(&str, &str)] = &[
+    ("b", "b"),
+    ("strong", "b"),
+    ("i", "i"),
+    ("em", "i"),
+    ("u", "u"),
+    ("ins", "u"),
+    ("s", "s"),
+    ("strike", "s"),
+    ("del", "s"),
+    ("code", "code"),
+    ("pre", "pre"),
+    ("a", "a"),
+];
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "li",
+    "tr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "section",
+    "article",
+    "figure",
+];
+const SKIP_CONTENT_TAGS: &[&str] = &["script", "style", "head", "title"];
+
+lazy_static! {
+    static ref TAG: Regex =
+        Regex::new(r"(?s)<!--.*?-->|<(/?)([a-zA-Z][a-zA-Z0-9]*)([^>]*)>").unwrap();
+}
+
+/// Convert HTML to the subset supported by Telegram,
+/// unsupported tags are removed and the visible text is truncated to `max_chars`
+pub fn sanitize_html(html: &str, max_chars: usize) -> String {
+    lazy_static! {
+        static ref HREF: Regex =
+            Regex::new(r#"(?i)href\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap();
+    }
+    let mut output = HtmlBuilder::new(max_chars);
+    let mut skipping: Option<String> = None;
+    let mut last = 0;
+    for cap in TAG.captures_iter(html) {
+        let m = cap.get(0).unwrap();
+        if skipping.is_none() {
+            output.push_text(&html[last..m.start()]);
+        }
+        last = m.end();
+        let name = match cap.get(2) {
+            Some(name) => name.as_str().to_ascii_lowercase(),
+            None => continue, // comment
+        };
+        let closing = !cap[1].is_empty();
+        if let Some(skipping_tag) = &skipping {
+            if closing && *skipping_tag == name {
+                skipping = None;
+            }
+            continue;
+        }
+        if output.is_full() {
+            break;
+        }
+        if SKIP_CONTENT_TAGS.contains(&&*name) {
+            if !closing && !cap[3].ends_with('/') {
+                skipping = Some(name);
+            }
+        } else if name == "br" {
+            output.push_newline(1);
+        } else if BLOCK_TAGS.contains(&&*name) {
+            output.push_newline(if name == "p" { 2 } else { 1 });
+        } else if let Some((_, tag)) = ALLOWED_TAGS.iter().find(|(alias, _)| *alias == name) {
+            if closing {
+                output.close_tag(tag);
+            } else if *tag == "a" {
+                let href = HREF.captures(&cap[3]).and_then(|c| {
+                    c.get(1)
+                        .or_else(|| c.get(2))
+                        .or_else(|| c.get(3))
+                        .map(|m| decode_entities(m.as_str()))
+                });
+                match href {
+                    Some(href) if href.starts_with("http://") || href.starts_with("https://") => {
+                        output.open_tag(tag, Some(&href))
+                    }
+                    // keep the text of the link, but nothing else
+                    _ => output.open_tag(tag, None),
+                }
+            } else {
+                output.open_tag(tag, None);
+            }
+        }
+    }
+    if skipping.is_none() && last < html.len() {
+        output.push_text(&html[last..]);
+    }
+    output.finish()
+}
+
+/// The text of HTML without any tags, f.e. to match it, block tags are turned into newlines
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut skipping: Option<String> = None;
+    let mut last = 0;
+    for cap in TAG.captures_iter(html) {
+        let m = cap.get(0).unwrap();
+        if skipping.is_none() {
+            text.push_str(&html[last..m.start()]);
+        }
+        last = m.end();
+        let name = match cap.get(2) {
+            Some(name) => name.as_str().to_ascii_lowercase(),
+            None => continue, // comment
+        };
+        let closing = !cap[1].is_empty();
+        if let Some(skipping_tag) = &skipping {
+            if closing && *skipping_tag == name {
+                skipping = None;
+            }
+            continue;
+        }
+        if SKIP_CONTENT_TAGS.contains(&&*name) {
+            if !closing && !cap[3].ends_with('/') {
+                skipping = Some(name);
+            }
+        } else if name == "br" || BLOCK_TAGS.contains(&&*name) {
+            text.push('\n');
+        }
+    }
+    if skipping.is_none() && last < html.len() {
+        text.push_str(&html[last..]);
+    }
+    decode_entities(&text)
+}
+
+pub fn decode_entities(s: &str) -> String {
+    match quick_xml::escape::unescape(s.as_bytes()) {
+        Ok(decoded) => String::from_utf8_lossy(&decoded).into_owned(),
+        Err(_) => s.to_owned(),
+    }
+}
+
+struct HtmlBuilder {
+    buf: String,
+    /// Tag name and whether it's written to `buf`
+    open_tags: Vec<(&'static str, bool)>,
+    chars_left: usize,
+    has_text: bool,
+    pending_newlines: usize,
+    pending_space: bool,
+    truncated: bool,
+}
+
+impl HtmlBuilder {
+    fn new(max_chars: usize) -> Self {
+        HtmlBuilder {
+            buf: String::new(),
+            open_tags: Vec::new(),
+            chars_left: max_chars,
+            has_text: false,
+            pending_newlines: 0,
+            pending_space: false,
+            truncated: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.truncated
+    }
+
+    fn push_newline(&mut self, n: usize) {
+        self.pending_newlines = cmp::max(self.pending_newlines, n);
+    }
+
+    /// Whitespace is collapsed and leading whitespace is removed
+    fn take_separator(&mut self) -> &'static str {
+        let separator = match (self.has_text, self.pending_newlines, self.pending_space) {
+            (false, _, _) => "",
+            (true, 0, true) => " ",
+            (true, 0, false) => "",
+            (true, 1, _) => "\n",
+            (true, _, _) => "\n\n",
+        };
+        self.pending_newlines = 0;
+        self.pending_space = false;
+        separator
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if self.truncated {
+            return;
+        }
+        let text = decode_entities(text);
+        for (i, word) in text.split(char::is_whitespace).enumerate() {
+            if i != 0 {
+                self.pending_space = true;
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let separator = self.take_separator();
+            let len = separator.len() + word.chars().count();
+            if len > self.chars_left {
+                let available = self.chars_left.saturating_sub(separator.len());
+                let partial: String = word.chars().take(available).collect();
+                self.buf.push_str(separator);
+                self.buf.push_str(&Escape(&partial).to_string());
+                self.buf.push('…');
+                self.truncated = true;
+                return;
+            }
+            self.chars_left -= len;
+            self.has_text = true;
+            self.buf.push_str(separator);
+            self.buf.push_str(&Escape(word).to_string());
+        }
+    }
+
+    fn open_tag(&mut self, tag: &'static str, href: Option<&str>) {
+        // Telegram doesn't support nested tags inside `pre` and `code`
+        let in_code = self
+            .open_tags
+            .iter()
+            .any(|(t, written)| *written && (*t == "pre" || *t == "code"));
+        if in_code || (tag == "a" && href.is_none()) {
+            self.open_tags.push((tag, false));
+            return;
+        }
+        let separator = self.take_separator();
+        self.chars_left = self.chars_left.saturating_sub(separator.len());
+        self.buf.push_str(separator);
+        match href {
+            Some(href) => self.buf.push_str(&format!("<a href=\"{}\">", Escape(href))),
+            None => self.buf.push_str(&format!("<{}>", tag)),
+        }
+        self.open_tags.push((tag, true));
+    }
+
+    fn close_tag(&mut self, tag: &'static str) {
+        if let Some(pos) = self.open_tags.iter().rposition(|(t, _)| *t == tag) {
+            for (t, written) in self.open_tags.drain(pos..).rev() {
+                if written {
+                    self.buf.push_str(&format!("</{}>", t));
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        for (t, written) in self.open_tags.drain(..).rev() {
+            if written {
+                self.buf.push_str(&format!("</{}>", t));
+            }
+        }
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn large_msg_first_items() {
+        let lines = ["a".repeat(3000), "b".repeat(3000), "c".into()];
+        let msgs = format_large_msg_indexed("head".into(), &lines, |line| line.clone());
+        let firsts: Vec<Option<usize>> = msgs.iter().map(|(_, first)| *first).collect();
+        assert_eq!(firsts, vec![Some(0), Some(1)]);
+        assert!(msgs[1].0.ends_with("\nc"));
+
+        let msgs = format_large_msg_indexed("a".repeat(4000), &lines, |line| line.clone());
+        let firsts: Vec<Option<usize>> = msgs.iter().map(|(_, first)| *first).collect();
+        assert_eq!(firsts, vec![None, Some(0), Some(1)]);
+        assert_eq!(
+            format_large_msg(String::new(), &lines[2..], |line| line.clone()),
+            vec!["c"]
+        );
+    }
+
+    #[test]
+    fn truncate_chars() {
+        assert_eq!(truncate("abc", 3), "abc");
+        assert_eq!(truncate("标题标题", 2), "标题…");
+        assert_eq!(truncate("", 0), "");
+    }
+
+    #[test]
+    fn sanitize_plain_text() {
+        assert_eq!(
+            sanitize_html("  a &lt;b&gt; &amp;  c\n d ", 100),
+            "a &lt;b&gt; &amp; c d"
+        );
+    }
+
+    #[test]
+    fn sanitize_tags() {
+        let html = r#"<div class="x"><p>Hello <strong>world</strong><img src="a.png"/></p>
+            <p>see <a href="https://example.com/?a=1&amp;b=2">this</a> and <a href="javascript:x">that</a></p>
+            <script>alert("<b>")</script><pre><code>let x = 1;</code></pre></div>"#;
+        assert_eq!(
+            sanitize_html(html, 100),
+            "Hello <b>world</b>\n\nsee <a href=\"https://example.com/?a=1&amp;b=2\">this</a> \
+             and that\n\n<pre>let x = 1;</pre>"
+        );
+    }
+
+    #[test]
+    fn sanitize_truncate() {
+        assert_eq!(sanitize_html("<b>abc def</b> ghi", 5), "<b>abc d…</b>");
+        assert_eq!(sanitize_html("abc", 3), "abc");
+        assert_eq!(sanitize_html("<i>a<br>b</i>", 100), "<i>a\nb</i>");
+    }
+
+    #[test]
+    fn text_of_html() {
+        let html =
+            r#"<p>Read <a href="http://release.com">this</a> &amp; that</p><style>p {}</style>x"#;
+        assert_eq!(html_to_text(html), "\nRead this & that\nx");
+    }
+
+    #[test]
+    fn sanitize_unclosed() {
+        assert_eq!(sanitize_html("<b><i>a</b>", 100), "<b><i>a</i></b>");
+        assert_eq!(sanitize_html("a</i>", 100), "a");
+    }
+}
@@ -1,14 +1,14 @@
 use std::io::Cursor;
-use std::io::Write;
+use std::io::{BufRead, Write};
 
 use chrono::Local;
 use quick_xml::events::attributes::Attribute;
 use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
-use quick_xml::Writer;
+use quick_xml::{Reader, Writer};
 
 use crate::data::Feed;
 
-pub fn into_opml(feeds: Vec<Feed>) -> String {
+pub fn into_opml(feeds: Vec<Feed>, subscriber: i64) -> String {
     let mut writer = Writer::new(Cursor::new(Vec::new()));
     let decl = BytesDecl::new(b"1.0", Some(b"UTF-8"), None);
     writer.write_event(Event::Decl(decl)).unwrap();
@@ -43,6 +43,10 @@ pub fn into_opml(feeds: Vec<Feed>) -> String {
                     outline.push_attribute(Attribute::from(("type", "rss")));
                     outline.push_attribute(Attribute::from(("text", feed.title.as_str())));
                     outline.push_attribute(Attribute::from(("xmlUrl", feed.link.as_str())));
+                    if let Some(filter) = feed.filters.get(&subscriber) {
+                        let filter = filter.to_string();
+                        outline.push_attribute(Attribute::from(("filter", filter.as_str())));
+                    }
                     writer.write_event(Event::Empty(outline))?;
                 }
                 Ok(())
@@ -54,6 +58,59 @@ pub fn into_opml(feeds: Vec<Feed>) -> String {
     unsafe { String::from_utf8_unchecked(writer.into_inner().into_inner()) }
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outline {
+    pub title: String,
+    pub link: String,
+    pub filter: Option<String>,
+}
+
+/// Collect every `<outline>` with a `xmlUrl`, groups are flattened
+pub fn from_opml<B: BufRead>(reader: B) -> quick_xml::Result<Vec<Outline>> {
+    let mut reader = Reader::from_reader(reader);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut outlines = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) | Event::Empty(ref e) if e.local_name() == b"outline" => {
+                if let Some(outline) = parse_outline(&reader, e)? {
+                    outlines.push(outline);
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(outlines)
+}
+
+fn parse_outline<B: BufRead>(
+    reader: &Reader<B>,
+    e: &BytesStart,
+) -> quick_xml::Result<Option<Outline>> {
+    let mut title = None;
+    let mut text = None;
+    let mut link = None;
+    let mut filter = None;
+    for attribute in e.attributes() {
+        let attribute = attribute?;
+        match attribute.key {
+            b"xmlUrl" => link = Some(attribute.unescape_and_decode_value(reader)?),
+            b"title" => title = Some(attribute.unescape_and_decode_value(reader)?),
+            b"text" => text = Some(attribute.unescape_and_decode_value(reader)?),
+            b"filter" => filter = Some(attribute.unescape_and_decode_value(reader)?),
+            _ => (),
+        }
+    }
+    Ok(link.filter(|link| !link.is_empty()).map(|link| Outline {
+        title: title.or(text).unwrap_or_default(),
+        link,
+        filter,
+    }))
+}
+
 // type of `attrs` is for zero allocation
 fn with_tag<'a, W, F>(
     writer: &mut Writer<W>,
@@ -84,6 +141,7 @@ fn test_to_opml() {
     let mut feed2 = Feed::default();
     feed2.title = "title2".into();
     feed2.link = "link2".into();
+    feed2.filters.insert(42, "+a -b".parse().unwrap());
     let feeds = vec![feed1, feed2];
     let r = format!(
         "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
@@ -95,10 +153,49 @@ fn test_to_opml() {
          </head>\
          <body>\
          <outline type=\"rss\" text=\"title1\" xmlUrl=\"link1\"/>\
-         <outline type=\"rss\" text=\"title2\" xmlUrl=\"link2\"/>\
+         <outline type=\"rss\" text=\"title2\" xmlUrl=\"link2\" filter=\"+a -b\"/>\
          </body>\
          </opml>",
         Local::now().format("%a, %d %b %Y %T %Z")
     );
-    assert_eq!(into_opml(feeds), r);
+    assert_eq!(into_opml(feeds, 42), r);
+}
+
+#[test]
+fn test_from_opml() {
+    let opml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+<head><title>Feeds</title></head>
+<body>
+<outline type="rss" text="title1" xmlUrl="link1" filter="+a -b"/>
+<outline text="group">
+    <outline text="text2" title="title2" xmlUrl="link2?a=1&amp;b=2"></outline>
+    <outline text="group">
+        <outline type="rss" text="title3" xmlUrl="link3"/>
+    </outline>
+</outline>
+<outline text="no url"/>
+</body>
+</opml>"#;
+    let outlines = from_opml(Cursor::new(opml)).unwrap();
+    assert_eq!(
+        outlines,
+        vec![
+            Outline {
+                title: "title1".into(),
+                link: "link1".into(),
+                filter: Some("+a -b".into()),
+            },
+            Outline {
+                title: "title2".into(),
+                link: "link2?a=1&b=2".into(),
+                filter: None,
+            },
+            Outline {
+                title: "title3".into(),
+                link: "link3".into(),
+                filter: None,
+            },
+        ]
+    );
 }
@@ -0,0 +1,449 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::data::{DataError, FeedId};
+use crate::messages::Escape;
+
+type SubscriberId = i64;
+
+/// A message waiting to be sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: u64,
+    pub chat: SubscriberId,
+    /// The text, or the caption if there's media
+    pub html: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<Media>,
+    /// Show a preview of this URL, previews are disabled otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_preview: Option<String>,
+    /// Record the message of the item once sent, to edit it later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item: Option<ItemRef>,
+    /// Edit this message instead of sending a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit: Option<u32>,
+}
+
+/// An item in `Database::sent_items`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemRef {
+    pub feed: FeedId,
+    /// From `data::item_key`
+    pub item: u64,
+}
+
+/// A file sent by URL, Telegram downloads it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Media {
+    pub kind: MediaKind,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Photo,
+    Audio,
+    Video,
+    Document,
+}
+
+impl MediaKind {
+    pub fn from_mime_type(mime_type: Option<&str>) -> MediaKind {
+        match mime_type.and_then(|mime| mime.split('/').next()) {
+            Some("image") => MediaKind::Photo,
+            Some("audio") => MediaKind::Audio,
+            Some("video") => MediaKind::Video,
+            _ => MediaKind::Document,
+        }
+    }
+
+    /// https://core.telegram.org/bots/api#sending-files
+    pub fn max_size_by_url(self) -> u64 {
+        match self {
+            MediaKind::Photo => 5 * 1024 * 1024,
+            _ => 20 * 1024 * 1024,
+        }
+    }
+}
+
+/// Messages are persisted before sending, and removed only after Telegram accepted them,
+/// so nothing is lost if the bot is restarted or Telegram is unreachable
+///
+/// Changes are appended to a journal by `flush`, one JSON record per line,
+/// the journal is rewritten once it's mostly removed messages
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    next_id: u64,
+    /// The queued messages of each chat, in order
+    queues: HashMap<SubscriberId, VecDeque<Envelope>>,
+    /// Changes not written yet
+    pending: Vec<Record<Envelope>>,
+    /// Records in the journal
+    journal_len: usize,
+    /// The journal must be rewritten, f.e. a failed write left a partial line
+    dirty: bool,
+    wakeup: Arc<Notify>,
+}
+
+/// Don't rewrite small journals
+const COMPACT_AFTER: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record<E> {
+    /// A new message, or a new version of the message with the same id
+    Push(E),
+    Remove(u64),
+}
+
+impl Outbox {
+    pub fn open(path: PathBuf) -> Result<Outbox, DataError> {
+        let mut next_id = 0;
+        // Ids increase, so they're in the order of the queue
+        let mut messages = BTreeMap::new();
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            let lines: Vec<&str> = content.lines().collect();
+            for (i, line) in lines.iter().enumerate() {
+                match serde_json::from_str(line) {
+                    Ok(Record::Push(msg)) => {
+                        let msg: Envelope = msg;
+                        next_id = cmp::max(next_id, msg.id + 1);
+                        messages.insert(msg.id, msg);
+                    }
+                    Ok(Record::Remove(id)) => {
+                        next_id = cmp::max(next_id, id + 1);
+                        messages.remove(&id);
+                    }
+                    // Cut off by a crash while writing
+                    Err(_) if i + 1 == lines.len() => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        let mut queues: HashMap<SubscriberId, VecDeque<Envelope>> = HashMap::new();
+        for msg in messages.into_values() {
+            queues.entry(msg.chat).or_default().push_back(msg);
+        }
+        let mut result = Outbox {
+            path,
+            next_id,
+            queues,
+            pending: Vec::new(),
+            journal_len: 0,
+            dirty: true,
+            wakeup: Arc::new(Notify::new()),
+        };
+        result.flush()?;
+        Ok(result)
+    }
+
+    /// Queue the message for every subscriber
+    pub fn push<I: IntoIterator<Item = SubscriberId>>(&mut self, subscribers: I, html: &str) {
+        self.push_envelopes(subscribers, html, None, None, None);
+    }
+
+    /// Queue the message with a preview of `link_preview` for every subscriber,
+    /// the messages are recorded if they show a single `item`
+    pub fn push_item<I: IntoIterator<Item = SubscriberId>>(
+        &mut self,
+        subscribers: I,
+        html: &str,
+        link_preview: Option<&str>,
+        item: Option<ItemRef>,
+    ) {
+        self.push_envelopes(subscribers, html, None, link_preview, item);
+    }
+
+    /// Queue an edit of a sent message
+    pub fn push_edit(
+        &mut self,
+        chat: SubscriberId,
+        message_id: u32,
+        html: &str,
+        link_preview: Option<&str>,
+    ) {
+        self.enqueue(Envelope {
+            id: self.next_id,
+            chat,
+            html: html.to_owned(),
+            media: None,
+            link_preview: link_preview.map(str::to_owned),
+            item: None,
+            edit: Some(message_id),
+        });
+        self.wakeup.notify_one();
+    }
+
+    /// Queue the media with `caption` for every subscriber
+    pub fn push_media<I: IntoIterator<Item = SubscriberId>>(
+        &mut self,
+        subscribers: I,
+        media: Media,
+        caption: &str,
+    ) {
+        self.push_envelopes(subscribers, caption, Some(media), None, None);
+    }
+
+    fn push_envelopes<I: IntoIterator<Item = SubscriberId>>(
+        &mut self,
+        subscribers: I,
+        html: &str,
+        media: Option<Media>,
+        link_preview: Option<&str>,
+        item: Option<ItemRef>,
+    ) {
+        for chat in subscribers {
+            self.enqueue(Envelope {
+                id: self.next_id,
+                chat,
+                html: html.to_owned(),
+                media: media.clone(),
+                link_preview: link_preview.map(str::to_owned),
+                item,
+                edit: None,
+            });
+        }
+        self.wakeup.notify_one();
+    }
+
+    fn enqueue(&mut self, msg: Envelope) {
+        self.next_id = cmp::max(self.next_id, msg.id + 1);
+        self.pending.push(Record::Push(msg.clone()));
+        self.queues.entry(msg.chat).or_default().push_back(msg);
+    }
+
+    /// The media can't be sent, send a link to it instead
+    pub fn fall_back_to_link(&mut self, chat: SubscriberId, id: u64) {
+        let msg = self
+            .queues
+            .get_mut(&chat)
+            .and_then(|queue| queue.iter_mut().find(|msg| msg.id == id));
+        if let Some(msg) = msg {
+            if let Some(media) = msg.media.take() {
+                msg.html = format!(
+                    "{}\n<a href=\"{}\">{}</a>",
+                    msg.html,
+                    Escape(&media.url),
+                    tr!("media_link")
+                );
+                self.pending.push(Record::Push(msg.clone()));
+            }
+        }
+    }
+
+    /// The oldest message to the chat
+    pub fn front_of(&self, chat: SubscriberId) -> Option<&Envelope> {
+        self.queues.get(&chat).and_then(VecDeque::front)
+    }
+
+    /// Chats with pending messages
+    pub fn chats(&self) -> HashSet<SubscriberId> {
+        self.queues.keys().copied().collect()
+    }
+
+    /// Notified when messages are pushed to new chats
+    pub fn wakeup(&self) -> Arc<Notify> {
+        self.wakeup.clone()
+    }
+
+    pub fn remove(&mut self, chat: SubscriberId, id: u64) -> bool {
+        let queue = match self.queues.get_mut(&chat) {
+            Some(queue) => queue,
+            None => return false,
+        };
+        // Almost always the front
+        let removed = match queue.iter().position(|msg| msg.id == id) {
+            Some(i) => queue.remove(i).is_some(),
+            None => false,
+        };
+        if queue.is_empty() {
+            self.queues.remove(&chat);
+        }
+        if removed {
+            self.pending.push(Record::Remove(id));
+        }
+        removed
+    }
+
+    /// Drop all messages to the chat, f.e. the bot was kicked
+    pub fn remove_chat(&mut self, chat: SubscriberId) {
+        let queue = self.queues.remove(&chat).unwrap_or_default();
+        self.pending
+            .extend(queue.iter().map(|msg| Record::Remove(msg.id)));
+    }
+
+    /// The group was upgraded to a supergroup
+    pub fn update_chat(&mut self, from: SubscriberId, to: SubscriberId) {
+        let queue = self.queues.remove(&from).unwrap_or_default();
+        for mut msg in queue {
+            msg.chat = to;
+            self.enqueue(msg);
+        }
+        self.wakeup.notify_one();
+    }
+
+    /// Write the changes since the last flush, call it once after each batch of changes
+    ///
+    /// The changes are kept on failure, and written with the next flush
+    pub fn flush(&mut self) -> Result<(), DataError> {
+        let len: usize = self.queues.values().map(VecDeque::len).sum();
+        let journal_len = self.journal_len + self.pending.len();
+        if self.dirty || journal_len > cmp::max(COMPACT_AFTER, len * 2) {
+            return self.compact(len);
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for record in &self.pending {
+            serde_json::to_writer(&mut buf, record)?;
+            buf.push(b'\n');
+        }
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(&buf)?;
+                file.sync_data()
+            });
+        if let Err(e) = result {
+            self.dirty = true;
+            return Err(e.into());
+        }
+        self.journal_len = journal_len;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// `flush` for callers with nothing better to do on failure than logging
+    pub fn flush_or_log(&mut self) {
+        if let Err(e) = self.flush() {
+            crate::print_error(e);
+        }
+    }
+
+    /// Rewrite the journal with only the queued messages
+    fn compact(&mut self, len: usize) -> Result<(), DataError> {
+        let file = AtomicFile::new(&self.path, OverwriteBehavior::AllowOverwrite);
+        file.write(|file| {
+            let mut writer = BufWriter::new(file);
+            for msg in self.queues.values().flatten() {
+                serde_json::to_writer(&mut writer, &Record::Push(msg))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()
+        })
+        .map_err(|e| match e {
+            atomicwrites::Error::Internal(e) | atomicwrites::Error::User(e) => DataError::Io(e),
+        })?;
+        self.journal_len = len;
+        self.pending.clear();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::test::TempPath;
+
+    fn queued(outbox: &Outbox) -> Vec<(u64, i64)> {
+        let mut queued: Vec<(u64, i64)> = outbox
+            .queues
+            .values()
+            .flatten()
+            .map(|m| (m.id, m.chat))
+            .collect();
+        queued.sort();
+        queued
+    }
+
+    #[test]
+    fn persistence() {
+        let path = TempPath::new("outbox", "jsonl");
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        outbox.push(vec![1, 2, 3], "<b>hello</b>");
+        outbox.update_chat(2, 20);
+        outbox.remove_chat(3);
+        assert_eq!(outbox.chats(), [1, 20].into_iter().collect());
+        let first = outbox.front_of(1).cloned().unwrap();
+        assert_eq!(first.html, "<b>hello</b>");
+        outbox.flush().unwrap();
+
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        assert_eq!(outbox.front_of(1), Some(&first));
+        assert!(outbox.remove(1, first.id));
+        assert!(!outbox.remove(1, first.id));
+        let item = ItemRef { feed: 1, item: 2 };
+        outbox.push_item(Some(4), "world", Some("https://example.com/"), Some(item));
+        let media = Media {
+            kind: MediaKind::from_mime_type(Some("audio/mpeg")),
+            url: "https://example.com/a.mp3".into(),
+            length: None,
+        };
+        outbox.push_media(Some(5), media, "caption");
+        outbox.fall_back_to_link(5, 4);
+        outbox.flush().unwrap();
+
+        let outbox = Outbox::open(path.clone()).unwrap();
+        assert_eq!(queued(&outbox), vec![(1, 20), (3, 4), (4, 5)]);
+        let msg = outbox.front_of(4).unwrap();
+        assert_eq!(msg.link_preview.as_deref(), Some("https://example.com/"));
+        assert_eq!(msg.item, Some(item));
+        let msg = outbox.front_of(5).unwrap();
+        assert_eq!(msg.media, None);
+        assert!(msg
+            .html
+            .starts_with("caption\n<a href=\"https://example.com/a.mp3\">"));
+    }
+
+    #[test]
+    fn journal() {
+        let path = TempPath::new("journal", "jsonl");
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        outbox.push(1..=COMPACT_AFTER as i64, "hello");
+        outbox.flush().unwrap();
+        let lines = || std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines(), COMPACT_AFTER);
+
+        let remove = |outbox: &mut Outbox, chats: std::ops::Range<i64>| {
+            for chat in chats {
+                let id = outbox.front_of(chat).unwrap().id;
+                outbox.remove(chat, id);
+                outbox.flush().unwrap();
+            }
+        };
+        // Appended until the journal is mostly removed messages
+        remove(&mut outbox, 1..101);
+        assert_eq!(lines(), COMPACT_AFTER + 100);
+        remove(&mut outbox, 101..1001);
+        assert!(lines() < COMPACT_AFTER);
+
+        // A partial line left by a crash
+        outbox.push(Some(1), "world");
+        outbox.flush().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"push\":{\"id\"").unwrap();
+        let outbox = Outbox::open(path.clone()).unwrap();
+        assert_eq!(outbox.front_of(1).map(|m| m.html.as_str()), Some("world"));
+        assert_eq!(queued(&outbox).len(), COMPACT_AFTER - 999);
+        assert_eq!(lines(), COMPACT_AFTER - 999);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Timelike, Utc};
+
+use crate::data::QuietHours;
+use crate::timezone::{format_time_of_day, parse_time_of_day, Timezone};
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Time left until quiet hours end, `None` if `now` is not in quiet hours
+pub fn remaining(quiet: &QuietHours, now: SystemTime) -> Option<Duration> {
+    let local = quiet.timezone.to_local(DateTime::<Utc>::from(now));
+    let now = local.num_seconds_from_midnight();
+    let (start, end) = (quiet.start * 60, quiet.end * 60);
+    let inside = if start <= end {
+        start <= now && now < end
+    } else {
+        // Across midnight
+        now >= start || now < end
+    };
+    if !inside {
+        return None;
+    }
+    let left = (end + SECONDS_PER_DAY - now) % SECONDS_PER_DAY;
+    Some(Duration::from_secs(left as u64))
+}
+
+/// Parse `HH:MM-HH:MM [timezone] [hold]`, f.e. `23:00-07:00 Asia/Shanghai`,
+/// `default_timezone` is used if the timezone is omitted
+pub fn parse_quiet_hours(args: &[&str], default_timezone: Timezone) -> Option<QuietHours> {
+    let (args, hold) = match args.split_last() {
+        Some((&"hold", rest)) => (rest, true),
+        _ => (args, false),
+    };
+    let (range, timezone) = match args {
+        [range] => (range, default_timezone),
+        [range, timezone] => (range, timezone.parse().ok()?),
+        _ => return None,
+    };
+    let (start, end) = range.split_once('-')?;
+    Some(QuietHours {
+        start: parse_time_of_day(start)?,
+        end: parse_time_of_day(end)?,
+        timezone,
+        hold,
+    })
+}
+
+/// The same format as `parse_quiet_hours` accepts
+pub fn format_quiet_hours(quiet: &QuietHours) -> String {
+    let mut result = format!(
+        "{}-{} {}",
+        format_time_of_day(quiet.start),
+        format_time_of_day(quiet.end),
+        quiet.timezone
+    );
+    if quiet.hold {
+        result.push_str(" hold");
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(s: &str) -> SystemTime {
+        SystemTime::from(DateTime::parse_from_rfc3339(s).unwrap())
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let quiet = parse_quiet_hours(&["23:00-07:30", "+08:00", "hold"], Timezone::UTC).unwrap();
+        assert_eq!(format_quiet_hours(&quiet), "23:00-07:30 UTC+08:00 hold");
+        assert_eq!(remaining(&quiet, at("2023-09-06T12:00:00Z")), None);
+        assert_eq!(
+            remaining(&quiet, at("2023-09-06T15:00:00Z")),
+            Some(Duration::from_secs(8 * 60 * 60 + 30 * 60))
+        );
+        assert_eq!(
+            remaining(&quiet, at("2023-09-06T23:00:00Z")),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(remaining(&quiet, at("2023-09-06T23:30:00Z")), None);
+
+        let quiet = parse_quiet_hours(&["01:00-02:00"], Timezone::UTC).unwrap();
+        assert!(!quiet.hold);
+        assert!(remaining(&quiet, at("2023-09-06T01:59:00Z")).is_some());
+        assert_eq!(remaining(&quiet, at("2023-09-06T02:00:00Z")), None);
+
+        let quiet = parse_quiet_hours(&["01:00-02:00", "hold"], Timezone::Fixed(-3600)).unwrap();
+        assert_eq!(format_quiet_hours(&quiet), "01:00-02:00 UTC-01:00 hold");
+
+        // Follows daylight saving time
+        let quiet = parse_quiet_hours(&["23:00-07:00", "Europe/Berlin"], Timezone::UTC).unwrap();
+        assert_eq!(format_quiet_hours(&quiet), "23:00-07:00 Europe/Berlin");
+        assert_eq!(
+            remaining(&quiet, at("2023-07-01T04:00:00Z")),
+            Some(Duration::from_secs(60 * 60))
+        );
+        assert_eq!(
+            remaining(&quiet, at("2023-01-01T04:00:00Z")),
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+        assert!(remaining(&quiet, at("2023-07-01T21:30:00Z")).is_some());
+        assert_eq!(remaining(&quiet, at("2023-01-01T21:30:00Z")), None);
+
+        assert_eq!(parse_quiet_hours(&["01:00"], Timezone::UTC), None);
+        assert_eq!(
+            parse_quiet_hours(&["01:00-02:00", "hold", "+8"], Timezone::UTC),
+            None
+        );
+        assert_eq!(
+            parse_quiet_hours(&["01:00-02:00", "Europe/Nowhere"], Timezone::UTC),
+            None
+        );
+    }
+}
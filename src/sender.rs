@@ -0,0 +1,368 @@
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use tbot::{
+    types::{input_file, parameters},
+    Bot,
+};
+use tokio::{
+    self,
+    sync::Mutex,
+    time::{self, Duration, Instant},
+};
+
+use crate::data::Database;
+use crate::i18n;
+use crate::messages::Escape;
+use crate::outbox::{Envelope, MediaKind, Outbox};
+use crate::quiet;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Notify the admins and the log chat when deliveries to a chat keep failing
+const REPORT_AFTER_FAILURES: u32 = 5;
+/// Held messages check the quiet hours again after this, in case they were changed
+const QUIET_RECHECK: Duration = Duration::from_secs(60);
+
+/// https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this
+const GLOBAL_SPACING: Duration = Duration::from_millis(1000 / 30);
+const PRIVATE_CHAT_SPACING: Duration = Duration::from_secs(1);
+const GROUP_SPACING: Duration = Duration::from_secs(60 / 20);
+
+static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(RateLimiter::default);
+
+/// Wait until a message can be sent to the chat without exceeding Telegram's limits,
+/// every message sent by the bot should wait for a slot first
+pub async fn wait_for_slot(chat: i64) {
+    let at = RATE_LIMITER.reserve(chat, Instant::now());
+    time::sleep_until(at).await;
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    state: std::sync::Mutex<RateLimiterState>,
+}
+
+#[derive(Default)]
+struct RateLimiterState {
+    /// Reserved slots of all chats, at least `GLOBAL_SPACING` apart
+    reserved: BTreeSet<Instant>,
+    next_chat: HashMap<i64, Instant>,
+}
+
+impl RateLimiter {
+    /// Slots are reserved in order, a chat can't get its next slot before its current one,
+    /// so waiting chats take turns
+    ///
+    /// A slot is the earliest instant after the chat's own wait with room for one more
+    /// message, so a chat waiting for its spacing doesn't hold up the others
+    fn reserve(&self, chat: i64, now: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        if state.next_chat.len() > 1024 {
+            state.next_chat.retain(|_, t| *t > now);
+        }
+        if let Some(expired) = now.checked_sub(GLOBAL_SPACING) {
+            state.reserved = state.reserved.split_off(&expired);
+        }
+        let chat_ready = state.next_chat.get(&chat).copied().unwrap_or(now);
+        let mut at = cmp::max(now, chat_ready);
+        let from = at.checked_sub(GLOBAL_SPACING);
+        let nearby = state
+            .reserved
+            .iter()
+            .skip_while(|&&slot| Some(slot) <= from);
+        for &slot in nearby {
+            if slot >= at + GLOBAL_SPACING {
+                break;
+            }
+            at = cmp::max(at, slot + GLOBAL_SPACING);
+        }
+        // Groups and channels have negative ids
+        let spacing = if chat < 0 {
+            GROUP_SPACING
+        } else {
+            PRIVATE_CHAT_SPACING
+        };
+        state.reserved.insert(at);
+        state.next_chat.insert(chat, at + spacing);
+        at
+    }
+}
+
+/// Drain the outbox, messages are removed only after Telegram responded
+///
+/// Every chat has its own worker, so a slow chat doesn't hold up the others
+///
+/// Failing deliveries are reported to `report_to`
+pub fn start(bot: Bot, db: Arc<Mutex<Database>>, outbox: Arc<Mutex<Outbox>>, report_to: Vec<i64>) {
+    let report_to: Arc<[i64]> = report_to.into();
+    let workers = Arc::new(std::sync::Mutex::new(HashSet::new()));
+    tokio::spawn(async move {
+        loop {
+            let wakeup = {
+                let outbox = outbox.lock().await;
+                let mut running = workers.lock().unwrap();
+                for chat in outbox.chats() {
+                    if running.insert(chat) {
+                        tokio::spawn(drain_chat(
+                            bot.clone(),
+                            db.clone(),
+                            outbox.clone(),
+                            workers.clone(),
+                            report_to.clone(),
+                            chat,
+                        ));
+                    }
+                }
+                outbox.wakeup()
+            };
+            wakeup.notified().await;
+        }
+    });
+}
+
+async fn drain_chat(
+    bot: Bot,
+    db: Arc<Mutex<Database>>,
+    outbox: Arc<Mutex<Outbox>>,
+    workers: Arc<std::sync::Mutex<HashSet<i64>>>,
+    report_to: Arc<[i64]>,
+    chat: i64,
+) {
+    use tbot::errors::MethodCall;
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let msg = {
+            let outbox = outbox.lock().await;
+            match outbox.front_of(chat) {
+                Some(msg) => msg.clone(),
+                None => {
+                    // Unregister while holding the outbox,
+                    // so new messages to this chat will start a new worker
+                    workers.lock().unwrap().remove(&chat);
+                    return;
+                }
+            }
+        };
+        let settings = db.lock().await.chat(chat);
+        let quiet_hours = settings.quiet_hours;
+        let left = quiet_hours.and_then(|quiet| quiet::remaining(&quiet, SystemTime::now()));
+        if let (Some(quiet), Some(left)) = (quiet_hours, left) {
+            if quiet.hold {
+                time::sleep(cmp::min(left, QUIET_RECHECK)).await;
+                continue;
+            }
+        }
+        if let Some(media) = &msg.media {
+            if media.length.unwrap_or(0) > media.kind.max_size_by_url() {
+                let mut outbox = outbox.lock().await;
+                i18n::with(settings.lang(), || outbox.fall_back_to_link(chat, msg.id));
+                outbox.flush_or_log();
+                continue;
+            }
+        }
+        wait_for_slot(chat).await;
+        let silent = settings.silent || left.is_some();
+        let result = send(&bot, &msg, silent).await;
+        match result {
+            Ok(message_id) => {
+                {
+                    let mut outbox = outbox.lock().await;
+                    outbox.remove(chat, msg.id);
+                    outbox.flush_or_log();
+                }
+                let mut db = db.lock().await;
+                db.reset_delivery_failure(chat);
+                if let Some(item) = msg.item {
+                    db.record_sent_message(item.feed, item.item, chat, message_id);
+                }
+            }
+            Err(MethodCall::RequestError { description, .. })
+                if chat_is_unavailable(&description) =>
+            {
+                db.lock().await.delete_subscriber(chat);
+                let mut outbox = outbox.lock().await;
+                outbox.remove_chat(chat);
+                outbox.flush_or_log();
+            }
+            Err(MethodCall::RequestError {
+                migrate_to_chat_id: Some(new_chat_id),
+                ..
+            }) => {
+                db.lock().await.update_subscriber(chat, new_chat_id.0);
+                let mut outbox = outbox.lock().await;
+                outbox.update_chat(chat, new_chat_id.0);
+                outbox.flush_or_log();
+            }
+            Err(MethodCall::RequestError {
+                retry_after: Some(delay),
+                ..
+            }) => {
+                time::sleep(Duration::from_secs(delay)).await;
+            }
+            Err(MethodCall::RequestError { .. }) if msg.edit.is_some() => {
+                // The message was deleted, or is the same after all
+                let mut outbox = outbox.lock().await;
+                outbox.remove(chat, msg.id);
+                outbox.flush_or_log();
+            }
+            Err(MethodCall::RequestError { .. }) if msg.media.is_some() => {
+                // Telegram failed to download the file, or it's not what the feed claims
+                let mut outbox = outbox.lock().await;
+                i18n::with(settings.lang(), || outbox.fall_back_to_link(chat, msg.id));
+                outbox.flush_or_log();
+                continue;
+            }
+            Err(e @ MethodCall::RequestError { .. }) => {
+                // Rejected by Telegram, retrying won't help
+                let error = e.to_string();
+                crate::print_error(e);
+                let count = db.lock().await.record_delivery_failure(chat, error.clone());
+                let mut outbox = outbox.lock().await;
+                outbox.remove(chat, msg.id);
+                report_failure(&mut outbox, &report_to, chat, count, &error);
+                outbox.flush_or_log();
+            }
+            Err(e) => {
+                // Network errors and the like, the message is kept and retried
+                let error = e.to_string();
+                crate::print_error(e);
+                let count = db.lock().await.record_delivery_failure(chat, error.clone());
+                {
+                    let mut outbox = outbox.lock().await;
+                    report_failure(&mut outbox, &report_to, chat, count, &error);
+                    outbox.flush_or_log();
+                }
+                time::sleep(backoff).await;
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                continue;
+            }
+        }
+        backoff = Duration::from_secs(1);
+    }
+}
+
+/// Report once per streak of failures, not to the failing chat itself
+fn report_failure(outbox: &mut Outbox, report_to: &[i64], chat: i64, count: u32, error: &str) {
+    if count != REPORT_AFTER_FAILURES {
+        return;
+    }
+    let report = tr!(
+        "delivery_failing",
+        chat = chat,
+        count = count,
+        error = Escape(error)
+    );
+    outbox.push(report_to.iter().copied().filter(|&to| to != chat), &report);
+}
+
+/// Send or edit the message, return the id of the message
+async fn send(bot: &Bot, msg: &Envelope, silent: bool) -> Result<u32, tbot::errors::MethodCall> {
+    let chat = tbot::types::chat::Id(msg.chat);
+    let text = parameters::Text::with_html(&msg.html);
+    let media = match &msg.media {
+        Some(media) => media,
+        None => {
+            // Telegram previews the first link, an invisible one chooses it
+            let html = msg
+                .link_preview
+                .as_ref()
+                .map(|url| format!("<a href=\"{}\">\u{2060}</a>{}", Escape(url), msg.html));
+            let text = match &html {
+                Some(html) => parameters::Text::with_html(html),
+                None => text,
+            };
+            let sent = match msg.edit {
+                Some(message_id) => {
+                    let message_id = tbot::types::message::Id(message_id);
+                    bot.edit_message_text(chat, message_id, text)
+                        .is_web_page_preview_disabled(html.is_none())
+                        .call()
+                        .await?
+                }
+                None => {
+                    bot.send_message(chat, text)
+                        .is_web_page_preview_disabled(html.is_none())
+                        .is_notification_disabled(silent)
+                        .call()
+                        .await?
+                }
+            };
+            return Ok(sent.id.0);
+        }
+    };
+    let url = media.url.as_str();
+    let sent = match media.kind {
+        MediaKind::Photo => {
+            let photo = input_file::Photo::with_url(url).caption(text);
+            bot.send_photo(chat, photo)
+                .is_notification_disabled(silent)
+                .call()
+                .await?
+        }
+        MediaKind::Audio => {
+            let audio = input_file::Audio::with_url(url).caption(text);
+            bot.send_audio(chat, audio)
+                .is_notification_disabled(silent)
+                .call()
+                .await?
+        }
+        MediaKind::Video => {
+            let video = input_file::Video::with_url(url).caption(text);
+            bot.send_video(chat, video)
+                .is_notification_disabled(silent)
+                .call()
+                .await?
+        }
+        MediaKind::Document => {
+            let document = input_file::Document::with_url(url).caption(text);
+            bot.send_document(chat, document)
+                .is_notification_disabled(silent)
+                .call()
+                .await?
+        }
+    };
+    Ok(sent.id.0)
+}
+
+pub fn chat_is_unavailable(s: &str) -> bool {
+    s.contains("Forbidden")
+        || s.contains("chat not found")
+        || s.contains("have no rights")
+        || s.contains("need administrator rights")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(1, now), now);
+        assert_eq!(limiter.reserve(2, now), now + GLOBAL_SPACING);
+        assert_eq!(limiter.reserve(1, now), now + PRIVATE_CHAT_SPACING);
+        assert_eq!(limiter.reserve(-1, now), now + 2 * GLOBAL_SPACING);
+        assert_eq!(
+            limiter.reserve(-1, now),
+            now + 2 * GLOBAL_SPACING + GROUP_SPACING
+        );
+    }
+
+    #[test]
+    fn busy_group_does_not_delay_others() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        for i in 0..10 {
+            assert_eq!(limiter.reserve(-1, now), now + i * GROUP_SPACING);
+        }
+        assert_eq!(limiter.reserve(1, now), now + GLOBAL_SPACING);
+        assert_eq!(limiter.reserve(2, now), now + 2 * GLOBAL_SPACING);
+        // Between the slots of the group
+        let later = now + GROUP_SPACING / 2;
+        assert_eq!(limiter.reserve(3, later), later);
+    }
+}
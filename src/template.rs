@@ -0,0 +1,350 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::data::Feed;
+use crate::feed::Item;
+use crate::messages::{sanitize_html, Escape};
+use crate::timezone::{format_item_date, Timezone};
+
+/// Visible length limit of `{summary}`
+const SUMMARY_MAX_CHARS: usize = 1024;
+/// Tags supported by Telegram
+const ALLOWED_TAGS: &[&str] = &[
+    "b", "strong", "i", "em", "u", "ins", "s", "strike", "del", "code", "pre", "a",
+];
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("template is empty")]
+    Empty,
+    #[error("unknown placeholder `{{{0}}}`")]
+    UnknownPlaceholder(String),
+    #[error("`{{` is not closed, use `{{{{` for a literal `{{`")]
+    UnclosedPlaceholder,
+    #[error("tag `{0}` is not supported by Telegram")]
+    UnsupportedTag(String),
+    #[error("tag `{0}` is not closed")]
+    UnclosedTag(String),
+    #[error("unexpected closing tag `{0}`")]
+    UnexpectedClosingTag(String),
+    #[error("`<` and `&` must be written as `&lt;` and `&amp;`")]
+    Unescaped,
+    #[error("`{{summary}}` can't be used inside tags")]
+    SummaryInTag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Feed,
+    FeedTag,
+    Title,
+    Link,
+    Author,
+    Date,
+    Summary,
+    Tags,
+}
+
+impl FromStr for Field {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "feed" => Field::Feed,
+            "feed_tag" => Field::FeedTag,
+            "title" => Field::Title,
+            "link" => Field::Link,
+            "author" => Field::Author,
+            "date" => Field::Date,
+            "summary" => Field::Summary,
+            "tags" => Field::Tags,
+            _ => return Err(TemplateError::UnknownPlaceholder(s.to_owned())),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    /// Telegram HTML, written as is
+    Html(String),
+    Field(Field),
+}
+
+/// Layout of an item in Telegram HTML, with placeholders
+///
+/// Syntax: `<b>{title}</b> {tags}`, placeholders are `{feed}`, `{feed_tag}`, `{title}`,
+/// `{link}`, `{author}`, `{date}`, `{summary}` and `{tags}`, `{{` and `}}` are literal braces.
+/// Values are escaped, except `{summary}` which is sanitized HTML.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Dates are shown in `timezone` if set
+    pub fn render(&self, feed: &Feed, item: &Item, timezone: Option<Timezone>) -> String {
+        let mut output = String::new();
+        for segment in &self.segments {
+            let field = match segment {
+                Segment::Html(html) => {
+                    output.push_str(html);
+                    continue;
+                }
+                Segment::Field(field) => field,
+            };
+            let value = match field {
+                Field::Feed => Escape(&feed.title).to_string(),
+                Field::FeedTag => hashtag(&feed.title).unwrap_or_default(),
+                Field::Title => Escape(item.title.as_deref().unwrap_or(&feed.title)).to_string(),
+                Field::Link => Escape(item.link.as_deref().unwrap_or(&feed.link)).to_string(),
+                Field::Author => Escape(item.author.as_deref().unwrap_or_default()).to_string(),
+                Field::Date => {
+                    Escape(&format_item_date(item, timezone).unwrap_or_default()).to_string()
+                }
+                Field::Summary => item
+                    .summary
+                    .as_ref()
+                    .or(item.content.as_ref())
+                    .map(|summary| sanitize_html(summary, SUMMARY_MAX_CHARS))
+                    .unwrap_or_default(),
+                Field::Tags => item
+                    .categories
+                    .iter()
+                    .filter_map(|category| hashtag(category))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            };
+            output.push_str(&value);
+        }
+        output.trim().to_owned()
+    }
+}
+
+/// Telegram hashtags only contain letters, digits and underscores
+fn hashtag(s: &str) -> Option<String> {
+    let tag: String = s
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    let tag = tag.trim_matches('_');
+    if tag.is_empty() {
+        None
+    } else {
+        Some(format!("#{}", tag))
+    }
+}
+
+/// Check the tags and entities, placeholders are already replaced by plain text
+fn validate_html(html: &str, summary_marker: &str) -> Result<(), TemplateError> {
+    lazy_static! {
+        static ref TOKEN: Regex = Regex::new(
+            r#"<(/?)([a-zA-Z][a-zA-Z0-9-]*)((?:\s+href\s*=\s*"[^"<]*")?)\s*>|&(?:lt|gt|amp|quot|#[0-9]+|#x[0-9a-fA-F]+);|[<&]"#
+        )
+        .unwrap();
+    }
+    let mut open_tags: Vec<String> = Vec::new();
+    let mut last = 0;
+    for cap in TOKEN.captures_iter(html) {
+        let m = cap.get(0).unwrap();
+        if html[last..m.start()].contains(summary_marker) && !open_tags.is_empty() {
+            return Err(TemplateError::SummaryInTag);
+        }
+        last = m.end();
+        let name = match cap.get(2) {
+            Some(name) => name.as_str().to_ascii_lowercase(),
+            None if m.as_str().len() == 1 => return Err(TemplateError::Unescaped),
+            None => continue, // entity
+        };
+        if m.as_str().contains(summary_marker) {
+            return Err(TemplateError::SummaryInTag);
+        }
+        let has_href = !cap[3].is_empty();
+        if !ALLOWED_TAGS.contains(&&*name) || (has_href && name != "a") {
+            return Err(TemplateError::UnsupportedTag(name));
+        }
+        if cap[1].is_empty() {
+            open_tags.push(name);
+        } else if open_tags.last() == Some(&name) {
+            open_tags.pop();
+        } else {
+            return Err(TemplateError::UnexpectedClosingTag(name));
+        }
+    }
+    if html[last..].contains(summary_marker) && !open_tags.is_empty() {
+        return Err(TemplateError::SummaryInTag);
+    }
+    match open_tags.pop() {
+        Some(tag) => Err(TemplateError::UnclosedTag(tag)),
+        None => Ok(()),
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Placeholders are replaced by these in the HTML to validate
+        const MARKER: &str = "x";
+        const SUMMARY_MARKER: &str = "\u{0}";
+
+        let source = s.trim();
+        if source.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        let mut segments = Vec::new();
+        let mut html = String::new();
+        let mut skeleton = String::new();
+        let mut rest = source;
+        while let Some(pos) = rest.find(&['{', '}'][..]) {
+            html.push_str(&rest[..pos]);
+            let (c, after) = (&rest[pos..pos + 1], &rest[pos + 1..]);
+            if after.starts_with(c) {
+                html.push_str(c);
+                rest = &after[1..];
+                continue;
+            }
+            if c == "}" {
+                // A lone `}` is harmless
+                html.push('}');
+                rest = after;
+                continue;
+            }
+            let end = after.find('}').ok_or(TemplateError::UnclosedPlaceholder)?;
+            let field: Field = after[..end].trim().parse()?;
+            skeleton.push_str(&html);
+            segments.push(Segment::Html(std::mem::take(&mut html)));
+            skeleton.push_str(if field == Field::Summary {
+                SUMMARY_MARKER
+            } else {
+                MARKER
+            });
+            segments.push(Segment::Field(field));
+            rest = &after[end + 1..];
+        }
+        html.push_str(rest);
+        skeleton.push_str(&html);
+        segments.push(Segment::Html(html));
+        validate_html(&skeleton, SUMMARY_MARKER)?;
+        segments.retain(|segment| !matches!(segment, Segment::Html(html) if html.is_empty()));
+        Ok(Template {
+            source: source.to_owned(),
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Template {}
+
+impl From<Template> for String {
+    fn from(template: Template) -> String {
+        template.source
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = TemplateError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed() -> Feed {
+        serde_json::from_str(
+            r#"{"link":"http://example.com/feed.xml","title":"Rust Blog","down_time":null,"subscribers":[],"ttl":null,"hash_list":[]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn render() {
+        let template: Template =
+            r#"{tags} <a href="{link}">{title}</a> — {author} {{x}} {feed_tag}"#
+                .parse()
+                .unwrap();
+        let item = Item {
+            title: Some("a < b".into()),
+            link: Some("http://example.com/?a=1&b=\"2\"".into()),
+            categories: vec!["release notes".into(), "c++".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render(&feed(), &item, None),
+            "#release_notes #c <a href=\"http://example.com/?a=1&amp;b=&quot;2&quot;\">a &lt; b</a> \
+             —  {x} #Rust_Blog"
+        );
+
+        let template: Template = "<b>{feed}</b>\n{summary}".parse().unwrap();
+        let item = Item {
+            summary: Some("<p>hello <script>x</script><b>world</b></p>".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render(&feed(), &item, None),
+            "<b>Rust Blog</b>\nhello <b>world</b>"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |s: &str| s.parse::<Template>().unwrap_err();
+        assert!(matches!(parse("  "), TemplateError::Empty));
+        assert!(matches!(parse("{name}"), TemplateError::UnknownPlaceholder(n) if n == "name"));
+        assert!(matches!(
+            parse("{title"),
+            TemplateError::UnclosedPlaceholder
+        ));
+        assert!(matches!(parse("<p>{title}</p>"), TemplateError::UnsupportedTag(t) if t == "p"));
+        assert!(matches!(parse("<b>{title}"), TemplateError::UnclosedTag(t) if t == "b"));
+        assert!(matches!(
+            parse("<b><i>x</b></i>"),
+            TemplateError::UnexpectedClosingTag(_)
+        ));
+        assert!(matches!(parse("a < b"), TemplateError::Unescaped));
+        assert!(matches!(parse("a & b"), TemplateError::Unescaped));
+        assert!(matches!(
+            parse("<b>{summary}</b>"),
+            TemplateError::SummaryInTag
+        ));
+        assert!(matches!(
+            parse(r#"<a href="{summary}">x</a>"#),
+            TemplateError::SummaryInTag
+        ));
+        assert!("&lt;{title}&gt; &amp; &#169;".parse::<Template>().is_ok());
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let template: Template = "<i>{title}</i>".parse().unwrap();
+        let json = serde_json::to_string(&template).unwrap();
+        assert_eq!(json, "\"<i>{title}</i>\"");
+        let template: Template = serde_json::from_str(&json).unwrap();
+        assert_eq!(template.to_string(), "<i>{title}</i>");
+        assert!(serde_json::from_str::<Template>("\"<p>\"").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::feed::Item;
+
+/// A zone name like `Europe/Berlin`, which follows daylight saving time, or a fixed offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timezone {
+    /// Seconds east of UTC
+    Fixed(i32),
+    Named(Tz),
+}
+
+impl Timezone {
+    pub const UTC: Timezone = Timezone::Fixed(0);
+
+    /// The offset from UTC at `time`
+    pub fn offset_at(&self, time: DateTime<Utc>) -> FixedOffset {
+        match *self {
+            Timezone::Fixed(offset) => fixed_offset(offset),
+            Timezone::Named(tz) => tz.offset_from_utc_datetime(&time.naive_utc()).fix(),
+        }
+    }
+
+    pub fn to_local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        time.with_timezone(&self.offset_at(time)).naive_local()
+    }
+
+    /// A repeated local time is its first occurrence, a local time skipped by
+    /// daylight saving time is before the jump, so it's passed once the clocks jump over it
+    pub fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let offset = match *self {
+            Timezone::Fixed(offset) => fixed_offset(offset),
+            Timezone::Named(tz) => match tz.from_local_datetime(&local).earliest() {
+                Some(time) => return time.with_timezone(&Utc),
+                None => tz
+                    .offset_from_utc_datetime(&(local + Duration::days(1)))
+                    .fix(),
+            },
+        };
+        Utc.from_utc_datetime(&(local - Duration::seconds(offset.local_minus_utc().into())))
+    }
+}
+
+/// `Europe/Berlin` or a UTC offset, see `parse_utc_offset`
+impl FromStr for Timezone {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(offset) = parse_utc_offset(s) {
+            return Ok(Timezone::Fixed(offset));
+        }
+        s.parse().map(Timezone::Named).map_err(|_| ())
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timezone::Fixed(offset) => f.write_str(&format_utc_offset(*offset)),
+            Timezone::Named(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+/// Fixed offsets are stored as seconds like before zone names were supported
+impl Serialize for Timezone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Timezone::Fixed(offset) => serializer.serialize_i32(*offset),
+            Timezone::Named(tz) => serializer.serialize_str(tz.name()),
+        }
+    }
+}
+
+/// Zone names unknown to this build (f.e. edited by hand) fall back to UTC
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Fixed(i32),
+            Named(String),
+        }
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Fixed(offset) => Timezone::Fixed(offset),
+            Stored::Named(name) => name.parse().map(Timezone::Named).unwrap_or_else(|_| {
+                eprintln!("Unknown timezone {}, using UTC", name);
+                Timezone::UTC
+            }),
+        })
+    }
+}
+
+/// `+8`, `-05:30`, `UTC+08:00`, return the offset in seconds
+pub fn parse_utc_offset(s: &str) -> Option<i32> {
+    let s = s
+        .strip_prefix("UTC")
+        .or_else(|| s.strip_prefix("utc"))
+        .unwrap_or(s);
+    let (sign, s) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = s.split_once(':').unwrap_or((s, "0"));
+    let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+pub fn format_utc_offset(offset: i32) -> String {
+    let minutes = offset.abs() / 60;
+    format!(
+        "UTC{}{:02}:{:02}",
+        if offset < 0 { '-' } else { '+' },
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// Invalid offsets (f.e. edited by hand) fall back to UTC
+fn fixed_offset(offset: i32) -> FixedOffset {
+    FixedOffset::east_opt(offset)
+        .or_else(|| FixedOffset::east_opt(0))
+        .unwrap()
+}
+
+/// The date of an item in the given timezone, or as the feed wrote it if it can't be parsed
+pub fn format_item_date(item: &Item, timezone: Option<Timezone>) -> Option<String> {
+    let date = match item.parsed_date() {
+        Some(date) => date,
+        None => return item.date.as_ref().map(|date| date.trim().to_owned()),
+    };
+    let date = match timezone {
+        Some(timezone) => date.with_timezone(&timezone.offset_at(date.with_timezone(&Utc))),
+        None => date,
+    };
+    Some(date.format("%Y-%m-%d %H:%M %:z").to_string())
+}
+
+/// Parse `HH:MM` to minutes after midnight
+pub fn parse_time_of_day(s: &str) -> Option<u32> {
+    use chrono::{NaiveTime, Timelike};
+    let time = NaiveTime::parse_from_str(s, "%H:%M").ok()?;
+    Some(time.num_seconds_from_midnight() / 60)
+}
+
+pub fn format_time_of_day(minute_of_day: u32) -> String {
+    format!("{:02}:{:02}", minute_of_day / 60 % 24, minute_of_day % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn utc_offset() {
+        assert_eq!(parse_utc_offset("+8"), Some(8 * 3600));
+        assert_eq!(parse_utc_offset("UTC-05:30"), Some(-(5 * 3600 + 30 * 60)));
+        assert_eq!(parse_utc_offset("8"), None);
+        assert_eq!(parse_utc_offset("+15"), None);
+        assert_eq!(format_utc_offset(-(5 * 3600 + 30 * 60)), "UTC-05:30");
+        assert_eq!(format_utc_offset(0), "UTC+00:00");
+    }
+
+    #[test]
+    fn timezone() {
+        let berlin: Timezone = "Europe/Berlin".parse().unwrap();
+        assert_eq!(berlin, Timezone::Named(Tz::Europe__Berlin));
+        assert_eq!(berlin.to_string(), "Europe/Berlin");
+        assert_eq!("+8".parse(), Ok(Timezone::Fixed(8 * 3600)));
+        assert_eq!("Europe/Nowhere".parse::<Timezone>(), Err(()));
+
+        let utc = |s| Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(s, "%F %R").unwrap());
+        let local = |s| NaiveDateTime::parse_from_str(s, "%F %R").unwrap();
+        let summer = utc("2023-07-01 12:00");
+        let winter = utc("2023-01-01 12:00");
+        assert_eq!(berlin.offset_at(summer).local_minus_utc(), 2 * 3600);
+        assert_eq!(berlin.offset_at(winter).local_minus_utc(), 3600);
+        assert_eq!(berlin.to_local(summer), local("2023-07-01 14:00"));
+        assert_eq!(berlin.to_utc(local("2023-01-01 13:00")), winter);
+        // 02:30 is skipped on the first day and repeated on the second
+        assert_eq!(
+            berlin.to_utc(local("2023-03-26 02:30")),
+            utc("2023-03-26 00:30")
+        );
+        assert_eq!(
+            berlin.to_utc(local("2023-10-29 02:30")),
+            utc("2023-10-29 00:30")
+        );
+
+        let stored = serde_json::to_string(&[berlin, Timezone::Fixed(-3600)]).unwrap();
+        assert_eq!(stored, r#"["Europe/Berlin",-3600]"#);
+        let loaded: Vec<Timezone> = serde_json::from_str(&stored).unwrap();
+        assert_eq!(loaded, [berlin, Timezone::Fixed(-3600)]);
+        let loaded: Timezone = serde_json::from_str(r#""Europe/Nowhere""#).unwrap();
+        assert_eq!(loaded, Timezone::UTC);
+    }
+
+    #[test]
+    fn item_date() {
+        let item = |date: &str| Item {
+            date: Some(date.into()),
+            ..Default::default()
+        };
+        let date = item("Wed, 06 Sep 2023 12:00:00 GMT");
+        assert_eq!(
+            format_item_date(&date, None).as_deref(),
+            Some("2023-09-06 12:00 +00:00")
+        );
+        assert_eq!(
+            format_item_date(&date, Some(Timezone::Fixed(8 * 3600))).as_deref(),
+            Some("2023-09-06 20:00 +08:00")
+        );
+        assert_eq!(
+            format_item_date(&date, "Europe/Berlin".parse().ok()).as_deref(),
+            Some("2023-09-06 14:00 +02:00")
+        );
+        assert_eq!(
+            format_item_date(&item(" yesterday "), Some(Timezone::UTC)).as_deref(),
+            Some("yesterday")
+        );
+        assert_eq!(format_item_date(&Item::default(), None), None);
+    }
+}
@@ -0,0 +1,202 @@
+//! Runs the bot in webhook mode against a stub Bot API given by `--api-uri`
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Instant};
+
+const TOKEN: &str = "123:TEST";
+const SECRET: &str = "s3cret";
+const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----\nTEST\n-----END CERTIFICATE-----\n";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Method and body of each request to the stub
+type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Answers every method with a result tbot can parse, `sendMessage` with a message
+async fn start_bot_api() -> (SocketAddr, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Requests::default();
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                // Connections are kept alive
+                while let Some((path, body)) = read_request(&mut stream).await {
+                    let method = path.rsplit('/').next().unwrap().to_owned();
+                    let result = match &*method {
+                        "getMe" => {
+                            r#"{"id":1,"is_bot":true,"first_name":"rssbot","username":"rssbot_test",
+                            "can_join_groups":true,"can_read_all_group_messages":false,
+                            "supports_inline_queries":false}"#
+                        }
+                        "sendMessage" => {
+                            r#"{"message_id":2,"date":0,"text":"ok",
+                            "chat":{"id":42,"type":"private","first_name":"a"}}"#
+                        }
+                        _ => "true",
+                    };
+                    recorded.lock().unwrap().push((method, body));
+                    let body = format!(r#"{{"ok":true,"result":{}}}"#, result);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                         content-length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, requests)
+}
+
+/// The path and body of a request with a `content-length`, `None` once the connection is closed
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<(String, String)> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let path = line.split_whitespace().nth(1)?.to_owned();
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some((path, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// POST JSON to the webhook server, return the status code
+async fn post_update(addr: SocketAddr, path: &str, update: &str) -> u16 {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nhost: {}\r\ncontent-type: application/json\r\n\
+         content-length: {}\r\nconnection: close\r\n\r\n{}",
+        path,
+        addr,
+        update.len(),
+        update
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+fn start_update(chat: i64) -> String {
+    format!(
+        r#"{{"update_id":{chat},"message":{{"message_id":1,"date":0,"text":"/start",
+        "entities":[{{"type":"bot_command","offset":0,"length":6}}],
+        "chat":{{"id":{chat},"type":"private","first_name":"a"}},
+        "from":{{"id":{chat},"is_bot":false,"first_name":"a"}}}}}}"#,
+        chat = chat
+    )
+}
+
+fn sent_to(requests: &Requests, chat: i64) -> bool {
+    let chat_id = format!(r#""chat_id":{}"#, chat);
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(method, body)| method == "sendMessage" && body.contains(&chat_id))
+}
+
+async fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Kills the bot and removes its files even if the test fails
+struct Bot {
+    process: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[tokio::test]
+async fn webhook() {
+    let (api_addr, requests) = start_bot_api().await;
+    let webhook_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("rssbot-webhook-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem");
+    std::fs::write(&cert, CERTIFICATE).unwrap();
+    let process = Command::new(env!("CARGO_BIN_EXE_rssbot"))
+        .arg("--database")
+        .arg(dir.join("rssbot.json"))
+        .args(["--api-uri", &format!("http://{}/", api_addr)])
+        .args(["--webhook-url", "https://example.com/rssbot"])
+        .args(["--webhook-listen", &webhook_addr.to_string()])
+        .args(["--webhook-secret", SECRET])
+        .arg("--webhook-cert")
+        .arg(&cert)
+        .arg(TOKEN)
+        .spawn()
+        .unwrap();
+    let _bot = Bot { process, dir };
+
+    let set_webhook = || {
+        let requests = requests.lock().unwrap();
+        let request = requests.iter().find(|(method, _)| method == "setWebhook");
+        request.map(|(_, body)| body.clone())
+    };
+    wait_for("setWebhook", || set_webhook().is_some()).await;
+    let body = set_webhook().unwrap();
+    assert!(body.contains(&format!("https://example.com/rssbot/{}", SECRET)));
+    // Uploaded in the multipart body
+    assert!(body.contains(CERTIFICATE.trim()));
+
+    // The server starts listening after setWebhook
+    let deadline = Instant::now() + TIMEOUT;
+    while TcpStream::connect(webhook_addr).await.is_err() {
+        assert!(Instant::now() < deadline, "webhook server not started");
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    post_update(webhook_addr, "/rssbot/wrong", &start_update(43)).await;
+    let path = format!("/rssbot/{}", SECRET);
+    let status = post_update(webhook_addr, &path, &start_update(42)).await;
+    assert_eq!(status, 200);
+    wait_for("the reply to /start", || sent_to(&requests, 42)).await;
+    // Handled concurrently, give the rejected one time to show up
+    sleep(Duration::from_millis(500)).await;
+    assert!(
+        !sent_to(&requests, 43),
+        "update on a wrong path was handled"
+    );
+}